@@ -0,0 +1,179 @@
+//! Captured command output: the child's stdout and stderr are streamed to
+//! the terminal as usual while the last lines are kept to store with the
+//! command.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::timestamp::Timestamp;
+
+/// Lines of output kept when `output_lines` is not configured.
+pub const DEFAULT_OUTPUT_LINES: usize = 20;
+
+/// How long to keep reading output once the command has exited. A process
+/// it left running in the background can hold the pipes open indefinitely.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The end of a command's output, as stored with the command.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CapturedOutput {
+    pub captured_at: Timestamp,
+    pub exit_code: i32,
+    /// The last lines of stdout and stderr. Each stream's lines keep their
+    /// order, but how the two streams interleave is only approximate
+    pub lines: Vec<String>,
+}
+
+/// The last `max` complete lines written to it.
+struct Tail {
+    max: usize,
+    lines: VecDeque<String>,
+}
+
+impl Tail {
+    fn new(max: usize) -> Self {
+        Tail {
+            max,
+            lines: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        self.lines
+            .push_back(line.strip_suffix('\r').unwrap_or(&line).to_string());
+        while self.lines.len() > self.max {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// Runs `command` with its stdout and stderr teed to ours, and returns its
+/// exit status with the last `max_lines` lines it printed, or why they could
+/// not be read. Output is piped, so programs that only color a terminal
+/// print plain text while captured.
+///
+/// Output is read for at most `DRAIN_TIMEOUT` after the command exits, so a
+/// process it started in the background does not keep shelf waiting; what
+/// such a process prints later is still passed through but not captured.
+pub fn run_captured(
+    command: &mut Command,
+    max_lines: usize,
+) -> io::Result<(ExitStatus, io::Result<Vec<String>>)> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let tail = Arc::new(Mutex::new(Tail::new(max_lines)));
+    let readers = [
+        child.stdout.take().map(|out| tee(out, io::stdout(), &tail)),
+        child.stderr.take().map(|err| tee(err, io::stderr(), &tail)),
+    ];
+
+    let status = child.wait()?;
+    let lines = drain(readers.into_iter().flatten(), &tail);
+    Ok((status, lines))
+}
+
+/// Waits up to `DRAIN_TIMEOUT` in all for `readers` to reach the end of
+/// their streams, and returns the lines in `tail`. Readers still blocked
+/// after that are left running.
+fn drain(
+    readers: impl Iterator<Item = JoinHandle<io::Result<()>>>,
+    tail: &Mutex<Tail>,
+) -> io::Result<Vec<String>> {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    for reader in readers {
+        while !reader.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if reader.is_finished() {
+            reader
+                .join()
+                .map_err(|_| io::Error::other("output reader panicked"))??;
+        }
+    }
+
+    let tail = tail
+        .lock()
+        .map_err(|_| io::Error::other("output reader panicked"))?;
+    Ok(tail.lines.iter().cloned().collect())
+}
+
+/// Copies `input` to `output` as it arrives, and complete lines to `tail`.
+/// Fails if `input` cannot be read; `output` going away is not an error.
+fn tee(
+    mut input: impl Read + Send + 'static,
+    mut output: impl Write + Send + 'static,
+    tail: &Arc<Mutex<Tail>>,
+) -> JoinHandle<io::Result<()>> {
+    let tail = Arc::clone(tail);
+    thread::spawn(move || {
+        let mut buffer = [0; 8192];
+        let mut line = Vec::new();
+
+        loop {
+            let read = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let chunk = &buffer[..read];
+            let _ = output.write_all(chunk).and_then(|_| output.flush());
+
+            for &byte in chunk {
+                if byte == b'\n' {
+                    if let Ok(mut tail) = tail.lock() {
+                        tail.push(&line);
+                    }
+                    line.clear();
+                } else {
+                    line.push(byte);
+                }
+            }
+        }
+
+        if !line.is_empty() {
+            if let Ok(mut tail) = tail.lock() {
+                tail.push(&line);
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::shell_command;
+
+    #[test]
+    fn test_run_captured_keeps_last_lines() {
+        let script = "for i in 1 2 3 4; do echo $i; done; printf 'no newline'; exit 4";
+        let (status, lines) = run_captured(&mut shell_command("sh", script), 3).unwrap();
+        assert_eq!(status.code(), Some(4));
+        assert_eq!(lines.unwrap(), vec!["3", "4", "no newline"]);
+
+        let (_, lines) = run_captured(&mut shell_command("sh", "echo oops >&2"), 3).unwrap();
+        assert_eq!(lines.unwrap(), vec!["oops"]);
+    }
+
+    #[test]
+    fn test_run_captured_does_not_wait_for_background_processes() {
+        let start = Instant::now();
+        let script = "sleep 5 & echo started";
+        let (status, lines) = run_captured(&mut shell_command("sh", script), 3).unwrap();
+        assert!(status.success());
+        assert_eq!(lines.unwrap(), vec!["started"]);
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+}
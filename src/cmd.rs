@@ -1,22 +1,63 @@
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Result};
 use copypasta::{ClipboardContext, ClipboardProvider};
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use shellexpand;
 use std::{
+    collections::{HashMap, HashSet},
     fmt::Display,
     fs,
-    io::{self, Write},
-    process::Command,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
 use crate::{
-    config::{ensure_data_dir_exists, get_data_path},
+    capture::{run_captured, CapturedOutput, DEFAULT_OUTPUT_LINES},
+    config::{get_data_path, Config},
+    editor::{edit_until_valid, editor_command},
     fuzzy::FuzzyPicker,
+    history::{
+        append_entry, format_duration, read_history, HistoryEntry, HistoryFilter,
+        DEFAULT_MAX_ENTRIES,
+    },
+    init::{init_script, RESERVED_NAMES, SHELL_BUILTINS},
+    output::{
+        print_record, print_records, CollectionRecord, CommandDetailRecord, CommandRecord,
+        HistoryRecord, OutputFormat, TagRecord, VariableRecord,
+    },
+    param_history::{forget_commands, record_values, ParamHistory},
+    prompt::read_line,
+    search::{
+        frecency, frecency_boost, rank, sort_commands, FirstField, MatchMode, Query, SortOrder,
+    },
+    shell::{exit_code, resolve_shell, shell_command},
+    shell_history::{
+        program_name, read_history as read_shell_history, recent_commands, HistoryShell,
+    },
+    storage::{read_toml, update_toml, with_lock, write_toml_atomic},
+    template::{
+        check_template, escape_placeholders, extract_parameters, highlight_placeholders,
+        interpolate_command, parse_parameters, resolve_values, Choices, ParamKind, ProvidedValues,
+        TemplateParam,
+    },
+    timestamp::Timestamp,
+    variables::{
+        effective_variables, find_local_file, resolve_variables, trust_file, LocalFile,
+        TrustedFiles, LOCAL_FILE,
+    },
 };
 extern crate colored; // not needed in Rust 2018+
 use colored::*;
 
+/// Name of the collection commands are stacked to when none is given.
+pub const DEFAULT_COLLECTION: &str = "global";
+
+/// Exit code when the requested command or collection is not on the shelf.
+pub const EXIT_NOT_FOUND: i32 = 66;
+/// Exit code when the shell running a saved command could not be spawned.
+pub const EXIT_SPAWN_FAILED: i32 = 71;
+/// Exit code when the fuzzy picker is closed without selecting a command.
+pub const EXIT_CANCELLED: i32 = 130;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SavedCommand {
     pub id: u32,
@@ -29,6 +70,51 @@ pub struct SavedCommand {
 
     #[serde(default = "default_is_template")]
     pub is_template: bool,
+
+    #[serde(default = "default_collection")]
+    pub collection: String,
+
+    /// Shell used to run this command, overriding the configured one.
+    pub shell: Option<String>,
+
+    /// Name of the shell alias or function `shelf init` defines to run it.
+    pub alias: Option<String>,
+
+    /// When the command was stacked. Unknown for commands from older shelves.
+    pub created_at: Option<Timestamp>,
+
+    /// When the command, its description, tags or collection last changed.
+    pub updated_at: Option<Timestamp>,
+
+    /// When the command was last run or copied.
+    pub last_run_at: Option<Timestamp>,
+
+    /// How many times the command was run or copied.
+    #[serde(default)]
+    pub run_count: u32,
+
+    /// The end of the output of the last captured run.
+    pub output: Option<CapturedOutput>,
+}
+
+impl SavedCommand {
+    fn mark_updated(&mut self) {
+        self.updated_at = Some(Timestamp::now());
+    }
+
+    fn mark_used(&mut self) {
+        self.last_run_at = Some(Timestamp::now());
+        self.run_count += 1;
+    }
+
+    /// The description, or `None` if the command still has the placeholder.
+    pub fn description_if_set(&self) -> Option<&str> {
+        if self.description == default_description() {
+            None
+        } else {
+            Some(&self.description)
+        }
+    }
 }
 
 fn default_is_template() -> bool {
@@ -39,6 +125,14 @@ fn default_description() -> String {
     "No description.".to_string()
 }
 
+fn default_collection() -> String {
+    DEFAULT_COLLECTION.to_string()
+}
+
+fn default_collections() -> Vec<String> {
+    vec![default_collection()]
+}
+
 // Used to display da' fuzz
 impl Display for SavedCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -52,10 +146,11 @@ impl Display for SavedCommand {
 
         write!(
             f,
-            "{} {} {} {} {}{}",
+            "{} {} {} {} {} {}{}",
             self.id.to_string().yellow(),
             "-".to_string().yellow(),
-            self.command.red().bold(),
+            format!("[{}]", self.collection).magenta(),
+            command_summary(&self.command).red().bold(),
             "--".to_string().yellow(),
             self.description.yellow(),
             tagout
@@ -65,103 +160,232 @@ impl Display for SavedCommand {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ShelfData {
+    #[serde(default = "default_collections")]
+    collections: Vec<String>,
     commands: Vec<SavedCommand>,
 }
 
-fn get_next_id(commands: &Vec<SavedCommand>) -> u32 {
-    commands.iter().map(|cmd| cmd.id).max().unwrap_or(0) + 1
-}
-
-fn extract_parameters(command: &str) -> Vec<String> {
-    let re = Regex::new(r"\{\{(\w+)\}\}").unwrap();
-    let mut params = Vec::new();
-    let mut seen = std::collections::HashSet::new();
-
-    for cap in re.captures_iter(command) {
-        let start_pos = cap.get(0).unwrap().start();
-
-        // Check if this match is escaped (preceded by backslash)
-        if start_pos > 0 && command.chars().nth(start_pos - 1) == Some('\\') {
-            continue; // Skip escaped templates
-        }
+impl ShelfData {
+    fn has_collection(&self, name: &str) -> bool {
+        self.collections.iter().any(|c| c == name)
+    }
 
-        let param = cap[1].to_string();
-        if seen.insert(param.clone()) {
-            params.push(param);
+    /// Adds `name` to the known collections, returning `true` if it was new.
+    fn ensure_collection(&mut self, name: &str) -> bool {
+        if self.has_collection(name) {
+            return false;
         }
+        self.collections.push(name.to_string());
+        true
     }
+}
 
-    params
+/// Tag filter used by `list` and `fuzz`. Plain tags must be present (all of
+/// them, or any of them with `any`), tags prefixed with `!` must be absent.
+#[derive(Default)]
+pub struct TagFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    any: bool,
 }
 
-fn prompt_for_parameters(
-    parameters: &[String],
-) -> Result<std::collections::HashMap<String, String>> {
-    let mut values = std::collections::HashMap::new();
+impl TagFilter {
+    pub fn new(tags: &[String], any: bool) -> Self {
+        let mut filter = TagFilter {
+            any,
+            ..Default::default()
+        };
+
+        for tag in tags {
+            match tag.strip_prefix('!') {
+                Some(negated) => filter.exclude.push(negated.to_string()),
+                None => filter.include.push(tag.clone()),
+            }
+        }
 
-    for param in parameters {
-        print!("Enter {}: ", param.yellow().bold());
-        io::stdout().flush()?;
+        filter
+    }
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let value = input.trim().to_string();
+    pub fn matches(&self, cmd: &SavedCommand) -> bool {
+        let tags = cmd.tags.as_deref().unwrap_or_default();
+        let has = |tag: &String| tags.contains(tag);
 
-        // if value.is_empty() {
-        //     return Err(anyhow::anyhow!("Parameter '{}' cannot be empty", param));
-        // }
+        if self.exclude.iter().any(has) {
+            return false;
+        }
+        if self.include.is_empty() {
+            return true;
+        }
 
-        values.insert(param.clone(), value);
+        if self.any {
+            self.include.iter().any(has)
+        } else {
+            self.include.iter().all(has)
+        }
     }
-
-    Ok(values)
 }
 
-fn interpolate_command(
-    command: &str,
-    values: &std::collections::HashMap<String, String>,
-) -> String {
-    let mut result = command.to_string();
-
-    for (param, value) in values {
-        let pattern = format!("{{{{{}}}}}", param);
-        result = result.replace(&pattern, value);
+/// Narrows the shelf down to the commands in `collection` (when given)
+/// matching `tags`.
+fn filter_commands(shelf_data: &mut ShelfData, collection: &Option<String>, tags: &TagFilter) {
+    if let Some(collection) = collection {
+        if !shelf_data.has_collection(collection) {
+            exit_collection_not_found(collection);
+        }
+        shelf_data
+            .commands
+            .retain(|cmd| &cmd.collection == collection);
     }
 
-    // Remove backslashes that were used to escape template syntax
-    result = result.replace("\\{{", "{{");
+    shelf_data.commands.retain(|cmd| tags.matches(cmd));
+}
 
-    result
+fn get_next_id(commands: &[SavedCommand]) -> u32 {
+    commands.iter().map(|cmd| cmd.id).max().unwrap_or(0) + 1
 }
 
-fn get_shelf_data() -> Result<ShelfData, Error> {
+fn get_shelf_data() -> Result<ShelfData> {
     let path = get_data_path(); // Path of the cmds.toml
-    if path.exists() {
-        let content = fs::read_to_string(&path)?;
-        let mut shelf_data: ShelfData =
-            toml::from_str(&content).context("Could not get toml data from string!")?;
-
+    if let Some(mut shelf_data) = read_toml::<ShelfData>(&path)? {
         // Migrate old commands that don't have is_template field
         for command in &mut shelf_data.commands {
-            if command.is_template == false && !extract_parameters(&command.command).is_empty() {
+            if !command.is_template && !extract_parameters(&command.command).is_empty() {
                 command.is_template = true;
             }
         }
 
+        // Migrate shelves from before collections existed, and make sure every
+        // collection referenced by a command is known.
+        shelf_data.ensure_collection(DEFAULT_COLLECTION);
+        let referenced: Vec<String> = shelf_data
+            .commands
+            .iter()
+            .map(|cmd| cmd.collection.clone())
+            .collect();
+        for collection in referenced {
+            shelf_data.ensure_collection(&collection);
+        }
+
         return Ok(shelf_data);
     }
 
-    Ok(ShelfData { commands: vec![] })
+    Ok(ShelfData {
+        collections: default_collections(),
+        commands: vec![],
+    })
+}
+
+/// Loads the shelf, lets `f` modify it, and writes it back. The whole cycle
+/// holds the data file lock, so concurrent shelf processes cannot overwrite
+/// each other's changes. Nothing is written if `f` returns an error.
+fn update_shelf_data<R>(f: impl FnOnce(&mut ShelfData) -> Result<R>) -> Result<R> {
+    let path = get_data_path();
+
+    with_lock(&path, || {
+        let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+        let result = f(&mut shelf_data)?;
+        write_toml_atomic(&path, &shelf_data).context("Could not write updated data to file!")?;
+        Ok(result)
+    })
+}
+
+fn exit_not_found(id: &u32) -> ! {
+    eprintln!(
+        "{}{}",
+        "Could not find saved command with id: ".red(),
+        id.to_string().yellow().bold()
+    );
+    std::process::exit(EXIT_NOT_FOUND)
+}
+
+fn exit_collection_not_found(name: &str) -> ! {
+    eprintln!(
+        "{}{}",
+        "Could not find collection: ".red(),
+        name.yellow().bold()
+    );
+    std::process::exit(EXIT_NOT_FOUND)
+}
+
+fn validate_collection_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_whitespace) {
+        return Err(anyhow::anyhow!(
+            "Collection names must be non-empty and contain no whitespace"
+        ));
+    }
+    Ok(())
+}
+
+/// Aliases become shell function names, so they are limited to characters
+/// every supported shell accepts there.
+fn validate_alias(alias: &str) -> Result<()> {
+    let valid = alias.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(anyhow::anyhow!(
+            "Aliases must start with a letter or _ and contain only letters, digits, - and _"
+        ));
+    }
+    if RESERVED_NAMES.contains(&alias) {
+        return Err(anyhow::anyhow!(
+            "{} is used by the code from shelf init and cannot be an alias",
+            alias
+        ));
+    }
+    Ok(())
+}
+
+fn warn_if_builtin(alias: &str) {
+    if SHELL_BUILTINS.contains(&alias) {
+        eprintln!(
+            "{} {}",
+            "Warning: this alias hides the shell builtin".yellow(),
+            alias.yellow().bold()
+        );
+    }
+}
+
+/// Fails if another command than `id` already has `alias`.
+fn check_alias_free(alias: &str, id: u32, commands: &[SavedCommand]) -> Result<()> {
+    match commands
+        .iter()
+        .find(|cmd| cmd.id != id && cmd.alias.as_deref() == Some(alias))
+    {
+        Some(other) => Err(anyhow::anyhow!(
+            "The alias {} is already used by command {}",
+            alias,
+            other.id
+        )),
+        None => Ok(()),
+    }
+}
+
+fn announce_new_collection(name: &str) {
+    println!(
+        "{} {}",
+        "Created new collection:".green(),
+        name.magenta().bold()
+    );
 }
 
 pub fn save_command(
     command: String,
     description: Option<String>,
     tags: Option<Vec<String>>,
-) -> Result<()> {
-    // Get file
-    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    collection: Option<String>,
+    shell: Option<String>,
+    alias: Option<String>,
+) -> Result<u32> {
+    let collection = collection.unwrap_or_else(default_collection);
+    validate_collection_name(&collection)?;
+    if let Some(alias) = &alias {
+        validate_alias(alias)?;
+        warn_if_builtin(alias);
+    }
 
+    check_template(&command)?;
     let parameters = extract_parameters(&command);
     let is_template = !parameters.is_empty();
 
@@ -173,37 +397,172 @@ pub fn save_command(
         );
     }
 
-    shelf_data.commands.push(SavedCommand {
-        id: get_next_id(&shelf_data.commands),
-        command: command.clone(),
-        description: match description {
-            Some(desc) => desc,
-            None => default_description(),
-        },
-        tags,
-        is_template,
-    });
-
-    // Ensure data directory exists before writing
-    ensure_data_dir_exists().context("Could not create data directory")?;
+    let now = Timestamp::now();
+    let (id, created) = update_shelf_data(|shelf_data| {
+        let created = shelf_data.ensure_collection(&collection);
+        let id = get_next_id(&shelf_data.commands);
+        if let Some(alias) = &alias {
+            check_alias_free(alias, id, &shelf_data.commands)?;
+        }
 
-    // Serialize data (save the command)
-    let toml_string =
-        toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-    fs::write(&get_data_path(), toml_string).context("Could not write command to data file!")?;
+        shelf_data.commands.push(SavedCommand {
+            id,
+            command: command.clone(),
+            description: match description {
+                Some(desc) => desc,
+                None => default_description(),
+            },
+            tags,
+            is_template,
+            collection: collection.clone(),
+            shell,
+            alias,
+            created_at: Some(now),
+            updated_at: Some(now),
+            last_run_at: None,
+            run_count: 0,
+            output: None,
+        });
+
+        Ok((id, created))
+    })?;
+
+    if created {
+        announce_new_collection(&collection);
+    }
 
     println!(
         "{} {} {}",
         "Shelved command:".green(),
-        command.cyan().bold(),
+        command_summary(&command).cyan().bold(),
         "succesfully".green()
     );
 
-    Ok(())
+    Ok(id)
+}
+
+/// Where `stack` reads a multi-line command from.
+pub enum ScriptSource {
+    File(PathBuf),
+    Stdin,
+    Editor,
+    /// The last N commands in the history of the shell in `$SHELL`, with
+    /// braces escaped like `shelf import history` does
+    ShellHistory(usize),
+}
+
+/// Reads a multi-line command, without trailing whitespace. Returns `None`
+/// if it was left empty in the editor.
+pub fn read_script(source: ScriptSource) -> Result<Option<String>> {
+    let script = match source {
+        ScriptSource::File(path) => fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?,
+        ScriptSource::Stdin => {
+            if io::stdin().is_terminal() {
+                eprintln!(
+                    "{}",
+                    "Reading the command from stdin, end it with Ctrl-D".bright_black()
+                );
+            }
+            io::read_to_string(io::stdin()).context("Could not read the command from stdin")?
+        }
+        ScriptSource::Editor => {
+            let initial = "# shelf: Write the command to stack below, it may span several lines.\n\
+                           # shelf: Save an empty file to stack nothing.\n";
+            let script = edit_until_valid(&editor_command(), initial, ".sh", |text| {
+                Ok(text.to_string())
+            })?;
+            match script {
+                Some(script) => script,
+                None => {
+                    println!("{}", "Nothing was stacked".yellow());
+                    return Ok(None);
+                }
+            }
+        }
+        ScriptSource::ShellHistory(count) => {
+            let shell = HistoryShell::detect();
+            let commands = recent_commands(shell, count)?;
+            if commands.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "No commands found in {}",
+                    shell.history_path().display()
+                ));
+            }
+
+            println!("{}", "From your shell history:".green());
+            for command in &commands {
+                println!("  {}", command.cyan().bold());
+            }
+            commands
+                .iter()
+                .map(|command| escape_placeholders(command))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    };
+
+    let script = script.trim_end();
+    if script.trim().is_empty() {
+        return Err(anyhow::anyhow!("Cannot stack an empty command"));
+    }
+    Ok(Some(script.to_string()))
+}
+
+/// Asks for a description and tags that were not given on the command
+/// line. Returns `false` if the prompt was cancelled.
+pub fn prompt_details(
+    description: &mut Option<String>,
+    tags: &mut Option<Vec<String>>,
+) -> Result<bool> {
+    if description.is_none() {
+        let prompt = format!("{} ", "Description:".yellow().bold());
+        let Some(answer) = read_line(&prompt, false, &[])? else {
+            return Ok(false);
+        };
+        *description = Some(answer.trim().to_string()).filter(|d| !d.is_empty());
+    }
+
+    if tags.is_none() {
+        let prompt = format!("{} ", "Tags (comma separated):".yellow().bold());
+        let Some(answer) = read_line(&prompt, false, &[])? else {
+            return Ok(false);
+        };
+        let list: Vec<String> = answer
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        *tags = Some(list).filter(|list| !list.is_empty());
+    }
+
+    Ok(true)
+}
+
+/// The first line of a multi-line command followed by how many lines it
+/// has left, e.g. `set -e [+4 lines]`. Single-line commands are unchanged.
+pub fn command_summary(command: &str) -> String {
+    let mut lines = command.lines();
+    let first = lines.next().unwrap_or_default();
+    match lines.count() {
+        0 => command.to_string(),
+        1 => format!("{} [+1 line]", first),
+        more => format!("{} [+{} lines]", first, more),
+    }
 }
 
-pub fn list_commands(verbose: &bool, reverse: &bool, limit: &Option<u32>) -> Result<()> {
+pub fn list_commands(
+    verbose: &bool,
+    reverse: &bool,
+    limit: &Option<u32>,
+    collection: &Option<String>,
+    tags: &TagFilter,
+    sort: SortOrder,
+    format: OutputFormat,
+) -> Result<()> {
     let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    filter_commands(&mut shelf_data, collection, tags);
+    sort_commands(&mut shelf_data.commands, sort);
 
     if *reverse {
         shelf_data.commands.reverse();
@@ -213,46 +572,144 @@ pub fn list_commands(verbose: &bool, reverse: &bool, limit: &Option<u32>) -> Res
         shelf_data.commands.truncate(*limit as usize);
     }
 
+    if format != OutputFormat::Human {
+        return print_command_records(format, &shelf_data.commands);
+    }
+
     if shelf_data.commands.is_empty() {
         println!("{}", "You have no saved commands!".red());
         return Ok(());
     }
 
-    shelf_data.commands.iter().for_each(|cmd| {
-        let mut output = format!(
-            "{} {} {}",
-            cmd.id.to_string().yellow().bold(),
-            "-".bright_yellow().bold(),
-            cmd.command.bright_cyan().bold(),
+    shelf_data
+        .commands
+        .iter()
+        .for_each(|cmd| print_command(cmd, verbose));
+    Ok(())
+}
+
+fn print_command_records<'a>(
+    format: OutputFormat,
+    commands: impl IntoIterator<Item = &'a SavedCommand>,
+) -> Result<()> {
+    let records: Vec<CommandRecord> = commands.into_iter().map(CommandRecord::from).collect();
+    print_records(format, &records)
+}
+
+fn print_command(cmd: &SavedCommand, verbose: &bool) {
+    let mut output = format!(
+        "{} {} {}",
+        cmd.id.to_string().yellow().bold(),
+        "-".bright_yellow().bold(),
+        command_summary(&cmd.command).bright_cyan().bold(),
+    );
+
+    if *verbose {
+        if let Some(description) = cmd.description_if_set() {
+            output.push_str(
+                format!(
+                    "\n  {} {}",
+                    "-- Desc: ".yellow().bold(),
+                    description.yellow()
+                )
+                .as_str(),
+            );
+        }
+
+        if let Some(tags) = &cmd.tags {
+            output.push_str(
+                format!(
+                    "\n  {} {}",
+                    "-- Tags: ".yellow().bold(),
+                    tags.join(", ").yellow()
+                )
+                .as_str(),
+            );
+        }
+
+        output.push_str(
+            format!(
+                "\n  {} {}",
+                "-- Collection: ".yellow().bold(),
+                cmd.collection.magenta()
+            )
+            .as_str(),
         );
 
-        if *verbose {
-            if cmd.description != "No description." {
-                output.push_str(
-                    format!(
-                        "\n  {} {}",
-                        "-- Desc: ".yellow().bold(),
-                        cmd.description.yellow()
-                    )
-                    .as_str(),
-                );
-            }
+        if let Some(alias) = &cmd.alias {
+            output.push_str(
+                format!("\n  {} {}", "-- Alias: ".yellow().bold(), alias.green()).as_str(),
+            );
+        }
 
-            if let Some(tags) = &cmd.tags {
-                output.push_str(
-                    format!(
-                        "\n  {} {}",
-                        "-- Tags: ".yellow().bold(),
-                        tags.join(", ").yellow()
-                    )
-                    .as_str(),
-                );
-            }
+        let runs = match cmd.last_run_at {
+            Some(last) => format!("{} (last {})", cmd.run_count, last.ago()),
+            None => cmd.run_count.to_string(),
+        };
+        output.push_str(format!("\n  {} {}", "-- Runs: ".yellow().bold(), runs).as_str());
+
+        if let Some(created) = cmd.created_at {
+            output.push_str(
+                format!(
+                    "\n  {} {}",
+                    "-- Created: ".yellow().bold(),
+                    created.ago().bright_black()
+                )
+                .as_str(),
+            );
+        }
+        if let Some(updated) = cmd
+            .updated_at
+            .filter(|updated| Some(*updated) != cmd.created_at)
+        {
+            output.push_str(
+                format!(
+                    "\n  {} {}",
+                    "-- Updated: ".yellow().bold(),
+                    updated.ago().bright_black()
+                )
+                .as_str(),
+            );
         }
+    }
 
-        println!("{}", output);
-    });
-    Ok(())
+    println!("{}", output);
+}
+
+/// Prints commands matching `query`, best match first. Returns `false` if
+/// nothing matched. `--first` output is plain text in every format.
+pub fn search_commands(
+    query: &str,
+    mode: MatchMode,
+    first: &Option<FirstField>,
+    verbose: &bool,
+    limit: &Option<u32>,
+    format: OutputFormat,
+) -> Result<bool> {
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    let query = Query::parse(query, mode)?;
+
+    let mut matches = rank(&shelf_data.commands, &query);
+    if let Some(limit) = limit {
+        matches.truncate(*limit as usize);
+    }
+
+    if matches.is_empty() {
+        if format != OutputFormat::Human && first.is_none() {
+            print_command_records(format, matches)?;
+        }
+        eprintln!("{}", "No saved commands matched your query.".red());
+        return Ok(false);
+    }
+
+    match first {
+        Some(FirstField::Id) => println!("{}", matches[0].id),
+        Some(FirstField::Command) => println!("{}", matches[0].command),
+        None if format != OutputFormat::Human => print_command_records(format, matches)?,
+        None => matches.iter().for_each(|cmd| print_command(cmd, verbose)),
+    }
+
+    Ok(true)
 }
 
 fn save_to_clipboard(cmd: &SavedCommand) -> Result<()> {
@@ -262,125 +719,322 @@ fn save_to_clipboard(cmd: &SavedCommand) -> Result<()> {
     println!(
         "{} {} {}",
         "Saved".green(),
-        cmd.command.cyan().bold(),
+        command_summary(&cmd.command).cyan().bold(),
         "to your clipboard.".green()
     );
 
     Ok(())
 }
 
-pub fn copy_command(id: &u32) -> Result<()> {
+/// Copies a command to the clipboard. `record` counts this as a use of the
+/// command, which is left out when it was just run anyway.
+pub fn copy_command(id: &u32, record: bool) -> Result<()> {
     let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
 
     if let Some(cmd) = shelf_data.commands.iter().find(|cmd| cmd.id == *id) {
-        return save_to_clipboard(cmd);
+        save_to_clipboard(cmd)?;
+        if record {
+            record_use(cmd.id);
+        }
+        return Ok(());
     }
 
-    eprintln!(
-        "{}{}",
-        "Could not find saved command with id: ".red(),
-        id.to_string().yellow().bold()
+    exit_not_found(id)
+}
+
+/// Bumps the usage statistics of a command that is being run or was copied.
+/// Failing to do so is only reported, it must not stop the command itself.
+fn record_use(id: u32) {
+    let result = update_shelf_data(|shelf_data| {
+        if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == id) {
+            cmd.mark_used();
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        eprintln!("{} {:#}", "Could not record command usage:".yellow(), e);
+    }
+}
+
+/// Runs a saved command, filling in template values first, and returns the
+/// exit code of the child process. With `capture`, the end of its output is
+/// stored with the command.
+fn exec_command(
+    command: SavedCommand,
+    config: &Config,
+    provided: &ProvidedValues,
+    capture: &bool,
+) -> Result<i32> {
+    let prepared = prepare_command(&command, config, provided)?;
+
+    record_use(command.id);
+    let capture = capture.then(|| config.output_lines.unwrap_or(DEFAULT_OUTPUT_LINES));
+    let log = RunLog::new(config);
+    let (code, output) = run_in_shell(
+        command.id,
+        &prepared.command,
+        &prepared.shell,
+        None,
+        capture,
+        &log,
     );
 
-    std::process::exit(1)
+    if let Some(output) = output {
+        store_output(command.id, output);
+    }
+
+    Ok(code)
 }
 
-fn exec_command(command: SavedCommand) -> Result<()> {
-    let final_command = if command.is_template {
-        let parameters = extract_parameters(&command.command);
-        if !parameters.is_empty() {
-            println!(
-                "{}",
-                "This is a template command. Please provide values:".yellow()
-            );
-            let values = prompt_for_parameters(&parameters)?;
-            interpolate_command(&command.command, &values)
-        } else {
-            command.command.clone()
-        }
+/// A saved command with its template values filled in.
+struct PreparedCommand {
+    /// The command to run
+    command: String,
+    shell: String,
+}
+
+/// Fills in the template values of a saved command, without running it.
+fn prepare_command(
+    command: &SavedCommand,
+    config: &Config,
+    provided: &ProvidedValues,
+) -> Result<PreparedCommand> {
+    let parameters = if command.is_template {
+        parse_parameters(&command.command)
     } else {
-        command.command.clone()
+        vec![]
     };
 
-    // First expand any environment variables in the command
-    let expanded_command = shellexpand::full(&final_command)
-        .map_err(|e| anyhow::anyhow!("Failed to expand environment variables: {}", e))?;
+    if parameters.is_empty() && !provided.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Command {} is not a template, but template values were given",
+            command.id
+        ));
+    }
 
-    // Split the expanded command string into parts
-    let args: Vec<String> = expanded_command
-        .split_whitespace()
-        .map(|s| s.to_string())
-        .collect();
+    let shell = resolve_shell(&[command.shell.as_deref(), config.shell.as_deref()]);
+
+    let final_command = if parameters.is_empty() {
+        // Only unescapes `\{{`
+        interpolate_command(&command.command, &HashMap::new(), &shell)
+    } else {
+        let previous = ParamHistory::load()
+            .context("Could not read template value history")?
+            .previous(command.id);
+        if provided.last && previous.last.is_none() {
+            return Err(anyhow::anyhow!(
+                "Command {} has not been run with template values yet",
+                command.id
+            ));
+        }
+
+        let variables = resolve_variables(config)?;
+        let values = resolve_values(
+            &parameters,
+            provided,
+            &variables,
+            &previous,
+            io::stdin().is_terminal(),
+            &shell,
+        )?;
+        if config.remember_values.unwrap_or(true) {
+            if let Err(e) = record_values(command.id, &values) {
+                eprintln!("{} {:#}", "Could not remember template values:".yellow(), e);
+            }
+        }
+        interpolate_command(&command.command, &values, &shell)
+    };
+
+    if final_command.trim().is_empty() {
+        return Err(anyhow::anyhow!("Cannot run an empty command"));
+    }
+
+    Ok(PreparedCommand {
+        command: final_command,
+        shell,
+    })
+}
+
+/// How `run_in_shell` logs a run to the run history.
+struct RunLog {
+    enabled: bool,
+    max_entries: usize,
+}
+
+impl RunLog {
+    fn new(config: &Config) -> Self {
+        RunLog {
+            enabled: config.history.unwrap_or(true),
+            max_entries: config.history_size.unwrap_or(DEFAULT_MAX_ENTRIES),
+        }
+    }
+}
 
-    if args.is_empty() {
-        return Err(anyhow::anyhow!("Empty command after expansion"));
+/// Runs a final command string with `shell`, in `cwd` or the current
+/// directory, and logs it to the run history as `log` says. Returns the exit
+/// code and, when `capture` is set, that many of the last lines of output.
+fn run_in_shell(
+    id: u32,
+    script: &str,
+    shell: &str,
+    cwd: Option<&Path>,
+    capture: Option<usize>,
+    log: &RunLog,
+) -> (i32, Option<CapturedOutput>) {
+    let started_at = Timestamp::now();
+    let start = Instant::now();
+
+    // Hand the whole string to the shell so pipes, redirects, quoting,
+    // globbing and variable expansion work exactly as when typed
+    let mut child = shell_command(shell, script);
+    if let Some(cwd) = cwd {
+        child.current_dir(cwd);
     }
 
-    let command_name = &args[0];
-    let params = &args[1..];
+    let result = match capture {
+        Some(max_lines) => run_captured(&mut child, max_lines).map(|(status, lines)| {
+            let lines = lines
+                .map_err(|e| eprintln!("{} {}", "Could not capture output:".yellow(), e))
+                .ok();
+            (status, lines)
+        }),
+        None => child.status().map(|status| (status, None)),
+    };
 
-    // Execute the command
-    match Command::new(command_name).args(params).status() {
-        Ok(status) => {
+    let (code, lines) = match result {
+        Ok((status, lines)) => {
             if !status.success() {
                 eprintln!("Command failed with status: {}", status);
             }
+            (exit_code(&status), lines)
+        }
+        Err(e) => {
+            eprintln!("Failed to execute command with {}: {}", shell, e);
+            (EXIT_SPAWN_FAILED, None)
+        }
+    };
+
+    if log.enabled {
+        let cwd = match cwd {
+            Some(cwd) => cwd.to_path_buf(),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let entry = HistoryEntry {
+            started_at,
+            id,
+            command: script.to_string(),
+            cwd: cwd.display().to_string(),
+            shell: shell.to_string(),
+            exit_code: code,
+            duration_ms: start.elapsed().as_millis() as u64,
+        };
+        if let Err(e) = append_entry(&entry, log.max_entries) {
+            eprintln!("{} {:#}", "Could not write run history:".yellow(), e);
+        }
+    }
+
+    let output = lines.map(|lines| CapturedOutput {
+        captured_at: started_at,
+        exit_code: code,
+        lines,
+    });
+    (code, output)
+}
+
+/// Stores captured output with a command, replacing what it had. Like usage
+/// statistics, failing to do so is only reported.
+fn store_output(id: u32, output: CapturedOutput) {
+    let count = output.lines.len();
+    let result = update_shelf_data(|shelf_data| {
+        if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == id) {
+            cmd.output = Some(output);
         }
-        Err(e) => eprintln!("Failed to execute command: {}, {:?}", e, args),
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => eprintln!(
+            "{}",
+            format!(
+                "Stored the last {} lines of output, see `shelf show {} --output`",
+                count, id
+            )
+            .bright_black()
+        ),
+        Err(e) => eprintln!("{} {:#}", "Could not store command output:".yellow(), e),
     }
-    return Ok(());
 }
 
-pub fn run_command(id: &u32) -> Result<()> {
+pub fn run_command(
+    id: &u32,
+    provided: &ProvidedValues,
+    capture: &bool,
+    config: &Config,
+) -> Result<i32> {
     let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
     if let Some(cmd) = shelf_data.commands.iter().find(|cmd| cmd.id == *id) {
-        return exec_command(cmd.clone());
+        return exec_command(cmd.clone(), config, provided, capture);
     }
-    eprintln!(
-        "{}{}",
-        "Could not find saved command with id: ".red(),
-        id.to_string().yellow().bold()
-    );
-    std::process::exit(1)
+    exit_not_found(id)
 }
 
-pub fn fuzzy_search(copy: &bool) -> Result<()> {
-    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+pub fn fuzzy_search(
+    copy: &bool,
+    print: &bool,
+    collection: &Option<String>,
+    tags: &TagFilter,
+    config: &Config,
+) -> Result<i32> {
+    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    filter_commands(&mut shelf_data, collection, tags);
 
     let mut picker = FuzzyPicker::new(&shelf_data.commands);
+    if config.frecency.unwrap_or(true) {
+        let now = Timestamp::now();
+        let boosts = shelf_data
+            .commands
+            .iter()
+            .map(|cmd| frecency_boost(frecency(cmd, now)))
+            .collect();
+        picker = picker.with_boosts(boosts);
+    }
 
     if let Ok(Some(selected)) = picker.pick() {
         if *copy {
-            return save_to_clipboard(&selected);
+            save_to_clipboard(&selected)?;
+            record_use(selected.id);
+            return Ok(0);
         }
 
-        return exec_command(selected);
-    } else {
-        println!("{}", "No saved command selected...".red().bold());
+        if *print {
+            // For shell widgets: the command is inserted at the prompt
+            // rather than run, so it can be edited first. It may never be
+            // run, so this is not counted as a use
+            let prepared = prepare_command(&selected, config, &ProvidedValues::default())?;
+            println!("{}", prepared.command);
+            return Ok(0);
+        }
+
+        return exec_command(selected, config, &ProvidedValues::default(), &false);
     }
 
-    Ok(())
+    eprintln!("{}", "No saved command selected...".red().bold());
+    Ok(EXIT_CANCELLED)
 }
 
 pub fn delete_command(id: &u32) -> Result<()> {
-    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
-
-    let initial_len = shelf_data.commands.len();
-    shelf_data.commands.retain(|cmd| cmd.id != *id);
+    update_shelf_data(|shelf_data| {
+        let initial_len = shelf_data.commands.len();
+        shelf_data.commands.retain(|cmd| cmd.id != *id);
 
-    if shelf_data.commands.len() == initial_len {
-        eprintln!(
-            "{}{}",
-            "Could not find saved command with id: ".red(),
-            id.to_string().yellow().bold()
-        );
-        std::process::exit(1);
-    }
+        if shelf_data.commands.len() == initial_len {
+            exit_not_found(id);
+        }
 
-    ensure_data_dir_exists().context("Could not create data directory")?;
-    let toml_string =
-        toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-    fs::write(&get_data_path(), toml_string).context("Could not write updated data to file!")?;
+        Ok(())
+    })?;
+    forget_commands(&[*id]).context("Could not forget template values")?;
 
     println!(
         "{} {} {}",
@@ -393,177 +1047,1015 @@ pub fn delete_command(id: &u32) -> Result<()> {
 }
 
 pub fn remove_tag(id: &u32, tag: &String) -> Result<()> {
-    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    update_shelf_data(|shelf_data| {
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
 
-    if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) {
         if let Some(tags) = &mut cmd.tags {
             let initial_len = tags.len();
             tags.retain(|t| t != tag);
 
             if tags.len() == initial_len {
-                eprintln!(
-                    "{}{} {} {}",
-                    "Tag ".red(),
-                    tag.yellow().bold(),
-                    "not found in command with id:".red(),
-                    id.to_string().yellow().bold()
-                );
-                std::process::exit(1);
+                return Err(anyhow::anyhow!(
+                    "Tag {} not found in command with id: {}",
+                    tag,
+                    id
+                ));
             }
 
             if tags.is_empty() {
                 cmd.tags = None;
             }
+            cmd.mark_updated();
         } else {
-            eprint!(
-                "{} {}",
-                "Command with id:".red(),
-                id.to_string().yellow().bold(),
-            );
-            eprintln!("{}", " has no tags to remove.".red());
-            std::process::exit(1);
+            return Err(anyhow::anyhow!(
+                "Command with id: {} has no tags to remove",
+                id
+            ));
         }
 
-        ensure_data_dir_exists().context("Could not create data directory")?;
-        let toml_string =
-            toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-        fs::write(&get_data_path(), toml_string)
-            .context("Could not write updated data to file!")?;
+        Ok(())
+    })?;
 
-        println!(
-            "{} {} {} {} {}",
-            "Removed tag".green(),
-            tag.yellow().bold(),
-            "from command with id:".green(),
-            id.to_string().yellow().bold(),
-            "successfully".green()
-        );
-    } else {
-        eprintln!(
-            "{}{}",
-            "Could not find saved command with id: ".red(),
-            id.to_string().yellow().bold()
-        );
-        std::process::exit(1);
-    }
+    println!(
+        "{} {} {} {} {}",
+        "Removed tag".green(),
+        tag.yellow().bold(),
+        "from command with id:".green(),
+        id.to_string().yellow().bold(),
+        "successfully".green()
+    );
 
     Ok(())
 }
 
 pub fn add_tag(id: &u32, tag: &String) -> Result<()> {
-    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    update_shelf_data(|shelf_data| {
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
 
-    if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) {
         if let Some(tags) = &mut cmd.tags {
             if tags.contains(tag) {
-                eprintln!(
-                    "{}{} {} {}",
-                    "Tag ".red(),
-                    tag.yellow().bold(),
-                    "already exists in command with id:".red(),
-                    id.to_string().yellow().bold()
-                );
-                std::process::exit(1);
+                return Err(anyhow::anyhow!(
+                    "Tag {} already exists in command with id: {}",
+                    tag,
+                    id
+                ));
             }
             tags.push(tag.clone());
         } else {
             cmd.tags = Some(vec![tag.clone()]);
         }
+        cmd.mark_updated();
 
-        ensure_data_dir_exists().context("Could not create data directory")?;
-        let toml_string =
-            toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-        fs::write(&get_data_path(), toml_string)
-            .context("Could not write updated data to file!")?;
+        Ok(())
+    })?;
 
+    println!(
+        "{} {} {} {} {}",
+        "Added tag".green(),
+        tag.yellow().bold(),
+        "to command with id:".green(),
+        id.to_string().yellow().bold(),
+        "successfully".green()
+    );
+
+    Ok(())
+}
+
+pub fn edit_description(id: &u32, new_description: &String) -> Result<()> {
+    let old_description = update_shelf_data(|shelf_data| {
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
+
+        cmd.mark_updated();
+        Ok(std::mem::replace(
+            &mut cmd.description,
+            new_description.clone(),
+        ))
+    })?;
+
+    println!(
+        "{} {} {} {}",
+        "Updated description for command with id:".green(),
+        id.to_string().yellow().bold(),
+        "successfully".green(),
+        format!("({} -> {})", old_description, new_description).bright_black()
+    );
+
+    Ok(())
+}
+
+pub fn edit_command_string(id: &u32, new_command: &str) -> Result<()> {
+    check_template(new_command)?;
+    let old_command = update_shelf_data(|shelf_data| {
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
+
+        cmd.mark_updated();
+        Ok(std::mem::replace(&mut cmd.command, new_command.to_string()))
+    })?;
+
+    println!(
+        "{} {} {} {}",
+        "Updated command with id:".green(),
+        id.to_string().yellow().bold(),
+        "successfully".green(),
+        format!(
+            "({} -> {})",
+            command_summary(&old_command),
+            command_summary(new_command)
+        )
+        .bright_black()
+    );
+
+    Ok(())
+}
+
+/// The parts of a saved command that `shelf edit` lets you change.
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct EditableCommand {
+    command: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default = "default_collection")]
+    collection: String,
+    #[serde(default)]
+    shell: String,
+    #[serde(default)]
+    alias: String,
+}
+
+impl EditableCommand {
+    fn from_command(cmd: &SavedCommand) -> Self {
+        EditableCommand {
+            command: cmd.command.clone(),
+            description: cmd.description_if_set().unwrap_or_default().to_string(),
+            tags: cmd.tags.clone().unwrap_or_default(),
+            collection: cmd.collection.clone(),
+            shell: cmd.shell.clone().unwrap_or_default(),
+            alias: cmd.alias.clone().unwrap_or_default(),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let edited: EditableCommand = toml::from_str(text)?;
+        if edited.command.trim().is_empty() {
+            return Err(anyhow::anyhow!("command must not be empty"));
+        }
+        validate_collection_name(&edited.collection)?;
+        check_template(&edited.command)?;
+        if !edited.alias.trim().is_empty() {
+            validate_alias(edited.alias.trim())?;
+        }
+        Ok(edited)
+    }
+
+    fn apply(self, cmd: &mut SavedCommand) {
+        let is_set = |s: &String| !s.trim().is_empty();
+
+        cmd.is_template = !extract_parameters(&self.command).is_empty();
+        cmd.command = self.command;
+        cmd.description = Some(self.description)
+            .filter(is_set)
+            .unwrap_or_else(default_description);
+        let tags: Vec<String> = self
+            .tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(is_set)
+            .collect();
+        cmd.tags = Some(tags).filter(|tags| !tags.is_empty());
+        cmd.collection = self.collection;
+        cmd.shell = Some(self.shell.trim().to_string()).filter(is_set);
+        cmd.alias = Some(self.alias.trim().to_string()).filter(is_set);
+        cmd.mark_updated();
+    }
+}
+
+/// Prints the `shelf init` code for `shell`. Commands whose alias was made
+/// invalid by hand-editing the data file are left out with a warning.
+pub fn print_init_script(shell: HistoryShell) -> Result<()> {
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    let aliased: Vec<SavedCommand> = shelf_data
+        .commands
+        .into_iter()
+        .filter(|cmd| match cmd.alias.as_deref().map(validate_alias) {
+            Some(Err(e)) => {
+                eprintln!(
+                    "{} {} {}",
+                    "Skipping the alias of command".yellow(),
+                    cmd.id.to_string().yellow().bold(),
+                    format!("{:#}", e).yellow()
+                );
+                false
+            }
+            Some(Ok(())) => true,
+            None => false,
+        })
+        .collect();
+
+    print!("{}", init_script(shell, &aliased));
+    Ok(())
+}
+
+/// A command from shell history, shown on one line in the picker.
+#[derive(Clone)]
+struct HistoryCommand(String);
+
+impl Display for HistoryCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", command_summary(&self.0))
+    }
+}
+
+/// History commands worth offering for import: most recent first, each
+/// once, and none that are already on the shelf. Braces are escaped, since
+/// `{{` in a command that was typed and run is not a placeholder.
+fn import_candidates(history: Vec<String>, shelved: &[SavedCommand]) -> Vec<String> {
+    let mut seen: HashSet<String> = shelved.iter().map(|cmd| cmd.command.clone()).collect();
+    history
+        .into_iter()
+        .rev()
+        .map(|command| escape_placeholders(command.trim()))
+        .filter(|command| seen.insert(command.clone()))
+        .collect()
+}
+
+/// Picks commands from a shell's history and stacks them all at once, with
+/// `tags`, and with their program as a tag if `tag_by_program` is set.
+pub fn import_history(
+    shell: Option<HistoryShell>,
+    tags: Option<Vec<String>>,
+    tag_by_program: &bool,
+    collection: Option<String>,
+) -> Result<()> {
+    let shell = shell.unwrap_or_else(HistoryShell::detect);
+    let collection = collection.unwrap_or_else(default_collection);
+    validate_collection_name(&collection)?;
+
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    let candidates = import_candidates(read_shell_history(shell)?, &shelf_data.commands);
+    if candidates.is_empty() {
+        println!("{}", "No new commands found in your shell history".yellow());
+        return Ok(());
+    }
+
+    let items: Vec<HistoryCommand> = candidates.into_iter().map(HistoryCommand).collect();
+    let picked = match FuzzyPicker::new(&items).pick_many() {
+        Ok(Some(picked)) => picked,
+        _ => {
+            println!("{}", "No commands imported".yellow());
+            return Ok(());
+        }
+    };
+
+    let now = Timestamp::now();
+    let (imported, created) = update_shelf_data(|shelf_data| {
+        let created = shelf_data.ensure_collection(&collection);
+        let mut imported = Vec::new();
+
+        for HistoryCommand(command) in picked {
+            let mut command_tags = tags.clone().unwrap_or_default();
+            if *tag_by_program {
+                if let Some(program) = program_name(&command) {
+                    if !command_tags.contains(&program) {
+                        command_tags.push(program);
+                    }
+                }
+            }
+
+            let id = get_next_id(&shelf_data.commands);
+            shelf_data.commands.push(SavedCommand {
+                id,
+                is_template: !extract_parameters(&command).is_empty(),
+                command: command.clone(),
+                description: default_description(),
+                tags: Some(command_tags).filter(|tags| !tags.is_empty()),
+                collection: collection.clone(),
+                shell: None,
+                created_at: Some(now),
+                updated_at: Some(now),
+                last_run_at: None,
+                run_count: 0,
+                alias: None,
+                output: None,
+            });
+            imported.push((id, command));
+        }
+
+        Ok((imported, created))
+    })
+    .context("Could not write commands to data file!")?;
+
+    if created {
+        announce_new_collection(&collection);
+    }
+    for (id, command) in &imported {
         println!(
-            "{} {} {} {} {}",
-            "Added tag".green(),
-            tag.yellow().bold(),
-            "to command with id:".green(),
+            "{} {} {}",
             id.to_string().yellow().bold(),
-            "successfully".green()
+            "-".bright_yellow().bold(),
+            command_summary(command).bright_cyan().bold()
         );
-    } else {
-        eprintln!(
-            "{}{}",
-            "Could not find saved command with id: ".red(),
-            id.to_string().yellow().bold()
+    }
+    println!(
+        "{} {} {}",
+        "Imported".green(),
+        imported.len().to_string().yellow().bold(),
+        "commands from your shell history".green()
+    );
+
+    Ok(())
+}
+
+/// Opens a command as TOML in `$VISUAL` or `$EDITOR` and saves the changes.
+pub fn edit_command(id: &u32) -> Result<()> {
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    let Some(cmd) = shelf_data.commands.iter().find(|cmd| cmd.id == *id) else {
+        exit_not_found(id)
+    };
+
+    let original = EditableCommand::from_command(cmd);
+    let toml = toml::to_string(&original).context("Could not render command as toml")?;
+    let initial = format!(
+        "# Editing command {}. Empty strings and lists leave a field unset.\n\
+         # Save an empty file to discard your changes.\n\n{}",
+        id, toml
+    );
+
+    let parse = |text: &str| {
+        let edited = EditableCommand::parse(text)?;
+        check_alias_free(edited.alias.trim(), *id, &shelf_data.commands)?;
+        Ok(edited)
+    };
+    let Some(edited) = edit_until_valid(&editor_command(), &initial, ".toml", parse)? else {
+        println!("{}", "Edit discarded, nothing was changed".yellow());
+        return Ok(());
+    };
+    if edited == original {
+        println!("{}", "No changes made".yellow());
+        return Ok(());
+    }
+
+    let (cmd, created) = update_shelf_data(|shelf_data| {
+        let created = shelf_data.ensure_collection(&edited.collection);
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
+
+        edited.apply(cmd);
+        let cmd = cmd.clone();
+        if let Some(alias) = &cmd.alias {
+            check_alias_free(alias, cmd.id, &shelf_data.commands)?;
+        }
+        Ok((cmd, created))
+    })?;
+
+    if created {
+        announce_new_collection(&cmd.collection);
+    }
+    if cmd.is_template {
+        println!(
+            "{} {}",
+            "Template detected with parameters:".yellow(),
+            extract_parameters(&cmd.command).join(", ").cyan().bold()
         );
-        std::process::exit(1);
     }
+    if let Some(alias) = &cmd.alias {
+        warn_if_builtin(alias);
+    }
+
+    println!(
+        "{} {} {}",
+        "Updated command with id:".green(),
+        id.to_string().yellow().bold(),
+        "successfully".green()
+    );
 
     Ok(())
 }
 
-pub fn edit_description(id: &u32, new_description: &String) -> Result<()> {
+pub fn move_command(id: &u32, collection: &str) -> Result<()> {
+    validate_collection_name(collection)?;
+
+    let (old_collection, created) = update_shelf_data(|shelf_data| {
+        let created = shelf_data.ensure_collection(collection);
+
+        let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) else {
+            exit_not_found(id);
+        };
+
+        cmd.mark_updated();
+        Ok((
+            std::mem::replace(&mut cmd.collection, collection.to_string()),
+            created,
+        ))
+    })?;
+
+    if created {
+        announce_new_collection(collection);
+    }
+
+    println!(
+        "{} {} {} {} {}",
+        "Moved command with id:".green(),
+        id.to_string().yellow().bold(),
+        "to".green(),
+        collection.magenta().bold(),
+        format!("({} -> {})", old_collection, collection).bright_black()
+    );
+
+    Ok(())
+}
+
+/// Prints every tag in use, most used first.
+pub fn list_tags(collection: &Option<String>, format: OutputFormat) -> Result<()> {
     let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    filter_commands(&mut shelf_data, collection, &TagFilter::default());
+
+    let counts = count_tags(&shelf_data.commands);
+    if format != OutputFormat::Human {
+        let records: Vec<TagRecord> = counts
+            .into_iter()
+            .map(|(tag, count)| TagRecord { tag, count })
+            .collect();
+        return print_records(format, &records);
+    }
+
+    if counts.is_empty() {
+        println!("{}", "You have no tagged commands!".red());
+        return Ok(());
+    }
+
+    for (tag, count) in counts {
+        println!(
+            "{} {}",
+            tag.yellow().bold(),
+            format!("({} commands)", count).bright_black()
+        );
+    }
+
+    Ok(())
+}
 
-    if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) {
-        let old_description = cmd.description.clone();
-        cmd.description = new_description.clone();
+fn count_tags(commands: &[SavedCommand]) -> Vec<(String, usize)> {
+    let mut counts = std::collections::HashMap::<&String, usize>::new();
+    for tag in commands
+        .iter()
+        .filter_map(|cmd| cmd.tags.as_ref())
+        .flatten()
+    {
+        *counts.entry(tag).or_default() += 1;
+    }
 
-        ensure_data_dir_exists().context("Could not create data directory")?;
-        let toml_string =
-            toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-        fs::write(&get_data_path(), toml_string)
-            .context("Could not write updated data to file!")?;
+    let mut counts: Vec<(String, usize)> = counts
+        .into_iter()
+        .map(|(tag, count)| (tag.clone(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
 
+pub fn list_collections(format: OutputFormat) -> Result<()> {
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+
+    let records: Vec<CollectionRecord> = shelf_data
+        .collections
+        .iter()
+        .map(|collection| CollectionRecord {
+            collection: collection.clone(),
+            count: shelf_data
+                .commands
+                .iter()
+                .filter(|cmd| &cmd.collection == collection)
+                .count(),
+        })
+        .collect();
+
+    if format != OutputFormat::Human {
+        return print_records(format, &records);
+    }
+
+    for record in records {
         println!(
-            "{} {} {} {}",
-            "Updated description for command with id:".green(),
-            id.to_string().yellow().bold(),
-            "successfully".green(),
-            format!("({} -> {})", old_description, new_description).bright_black()
+            "{} {}",
+            record.collection.magenta().bold(),
+            format!("({} commands)", record.count).bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn create_collection(name: &str) -> Result<()> {
+    validate_collection_name(name)?;
+
+    update_shelf_data(|shelf_data| {
+        if !shelf_data.ensure_collection(name) {
+            return Err(anyhow::anyhow!("Collection '{}' already exists", name));
+        }
+        Ok(())
+    })?;
+
+    announce_new_collection(name);
+
+    Ok(())
+}
+
+pub fn rename_collection(name: &str, new_name: &str) -> Result<()> {
+    validate_collection_name(new_name)?;
+
+    if name == DEFAULT_COLLECTION {
+        return Err(anyhow::anyhow!(
+            "The '{}' collection cannot be renamed",
+            DEFAULT_COLLECTION
+        ));
+    }
+
+    update_shelf_data(|shelf_data| {
+        if !shelf_data.has_collection(name) {
+            exit_collection_not_found(name);
+        }
+        if shelf_data.has_collection(new_name) {
+            return Err(anyhow::anyhow!("Collection '{}' already exists", new_name));
+        }
+
+        for collection in shelf_data.collections.iter_mut() {
+            if collection == name {
+                *collection = new_name.to_string();
+            }
+        }
+        for cmd in shelf_data.commands.iter_mut() {
+            if cmd.collection == name {
+                cmd.collection = new_name.to_string();
+            }
+        }
+
+        Ok(())
+    })?;
+
+    println!(
+        "{} {}",
+        "Renamed collection".green(),
+        format!("({} -> {})", name, new_name).bright_black()
+    );
+
+    Ok(())
+}
+
+/// Deletes a collection. Its commands are moved to the default collection,
+/// unless `purge` is set, in which case they are deleted along with it.
+pub fn delete_collection(name: &str, purge: &bool) -> Result<()> {
+    if name == DEFAULT_COLLECTION {
+        return Err(anyhow::anyhow!(
+            "The '{}' collection cannot be deleted",
+            DEFAULT_COLLECTION
+        ));
+    }
+
+    let (moved, purged) = update_shelf_data(|shelf_data| {
+        if !shelf_data.has_collection(name) {
+            exit_collection_not_found(name);
+        }
+
+        shelf_data.collections.retain(|c| c != name);
+
+        let mut moved = 0;
+        let mut purged = Vec::new();
+        if *purge {
+            shelf_data.commands.retain(|cmd| {
+                if cmd.collection == name {
+                    purged.push(cmd.id);
+                }
+                cmd.collection != name
+            });
+        } else {
+            for cmd in shelf_data.commands.iter_mut() {
+                if cmd.collection == name {
+                    cmd.collection = default_collection();
+                    moved += 1;
+                }
+            }
+        }
+
+        Ok((moved, purged))
+    })?;
+    forget_commands(&purged).context("Could not forget template values")?;
+
+    println!(
+        "{} {} {}",
+        "Deleted collection".green(),
+        name.magenta().bold(),
+        "successfully".green()
+    );
+    if moved > 0 {
+        println!(
+            "{}",
+            format!("Moved {} command(s) to '{}'", moved, DEFAULT_COLLECTION).bright_black()
+        );
+    }
+    if !purged.is_empty() {
+        println!(
+            "{}",
+            format!("Deleted {} command(s)", purged.len()).bright_black()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn list_variables(config: &Config, format: OutputFormat) -> Result<()> {
+    let variables = effective_variables(config)?;
+    variables.warn_untrusted();
+    let variables = variables.variables;
+
+    if format != OutputFormat::Human {
+        let records: Vec<VariableRecord> = variables
+            .into_iter()
+            .map(|var| VariableRecord {
+                name: var.name,
+                value: var.value,
+                source: var.source,
+            })
+            .collect();
+        return print_records(format, &records);
+    }
+
+    if variables.is_empty() {
+        println!("{}", "No variables set".bright_black());
+        return Ok(());
+    }
+
+    for var in variables {
+        println!(
+            "{} = {} {}",
+            var.name.yellow().bold(),
+            var.value,
+            format!("({})", var.source).bright_black()
         );
+    }
+
+    Ok(())
+}
+
+/// Changes a `.shelf.toml`. A new file or one that was trusted stays
+/// trusted with the changes, since they are the user's own.
+fn update_local_file<R>(path: &Path, f: impl FnOnce(&mut LocalFile) -> Result<R>) -> Result<R> {
+    let keep_trust = !path.exists() || TrustedFiles::load()?.is_trusted(path)?;
+    let result = update_toml(path, f)?;
+    if keep_trust {
+        trust_file(path)?;
+    }
+    Ok(result)
+}
+
+/// Trusts the nearest `.shelf.toml` as it is now, so its variables are used.
+pub fn trust_variables() -> Result<()> {
+    let cwd = std::env::current_dir().context("Could not get the current directory")?;
+    let path = find_local_file(&cwd).ok_or_else(|| {
+        anyhow::anyhow!("No {} in the current directory or its parents", LOCAL_FILE)
+    })?;
+
+    let local = read_toml::<LocalFile>(&path)?.unwrap_or_default();
+    trust_file(&path)?;
+
+    println!("{} {}", "Trusted".green(), path.display());
+    for (name, value) in local.variables {
+        println!("  {} = {}", name.yellow().bold(), value);
+    }
+
+    Ok(())
+}
+
+/// Where `shelf vars set/unset --local` writes: the nearest `.shelf.toml`,
+/// or a new one in the current directory.
+fn local_variables_path() -> Result<PathBuf> {
+    let cwd = std::env::current_dir().context("Could not get the current directory")?;
+    Ok(find_local_file(&cwd).unwrap_or_else(|| cwd.join(LOCAL_FILE)))
+}
+
+pub fn set_variable(name: &str, value: &str, local: &bool, config_path: &Path) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(anyhow::anyhow!(
+            "Invalid variable name '{}'. Use letters, digits and underscores",
+            name
+        ));
+    }
+
+    let path = if *local {
+        let path = local_variables_path()?;
+        update_local_file(&path, |file| {
+            file.variables.insert(name.to_string(), value.to_string());
+            Ok(())
+        })?;
+        path
     } else {
-        eprintln!(
-            "{}{}",
-            "Could not find saved command with id: ".red(),
-            id.to_string().yellow().bold()
+        update_toml(config_path, |config: &mut Config| {
+            config.variables.insert(name.to_string(), value.to_string());
+            Ok(())
+        })?;
+        config_path.to_path_buf()
+    };
+
+    println!(
+        "{} {} {} {}",
+        "Set".green(),
+        name.yellow().bold(),
+        "in".green(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+pub fn unset_variable(name: &str, local: &bool, config_path: &Path) -> Result<()> {
+    let (path, removed) = if *local {
+        let path = local_variables_path()?;
+        let removed = path.exists()
+            && update_local_file(&path, |file| Ok(file.variables.remove(name).is_some()))?;
+        (path, removed)
+    } else {
+        let removed = update_toml(config_path, |config: &mut Config| {
+            Ok(config.variables.remove(name).is_some())
+        })?;
+        (config_path.to_path_buf(), removed)
+    };
+
+    if !removed {
+        return Err(anyhow::anyhow!(
+            "Variable '{}' is not set in {}",
+            name,
+            path.display()
+        ));
+    }
+
+    println!(
+        "{} {} {} {}",
+        "Unset".green(),
+        name.yellow().bold(),
+        "in".green(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Prints everything known about a command. With `output`, only the output
+/// stored by its last captured run is printed, as plain lines.
+pub fn show_command(id: &u32, output: &bool, format: OutputFormat) -> Result<()> {
+    let shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+    let Some(cmd) = shelf_data.commands.iter().find(|cmd| cmd.id == *id) else {
+        exit_not_found(id)
+    };
+
+    if *output {
+        match &cmd.output {
+            Some(output) if format != OutputFormat::Human => print_record(format, output)?,
+            Some(output) => {
+                eprintln!("{}", describe_output(output).bright_black());
+                output.lines.iter().for_each(|line| println!("{}", line));
+            }
+            None => eprintln!(
+                "{} {}",
+                "No output stored for this command yet. Capture it with".yellow(),
+                format!("shelf run --capture {}", id).cyan().bold()
+            ),
+        }
+        return Ok(());
+    }
+
+    if format != OutputFormat::Human {
+        return print_record(format, &CommandDetailRecord::from(cmd));
+    }
+
+    let highlighted = highlight_placeholders(&cmd.command, |p| p.magenta().bold().to_string());
+    if cmd.command.contains('\n') {
+        println!("{}", cmd.id.to_string().yellow().bold());
+        for line in highlighted.lines() {
+            println!("    {}", line);
+        }
+    } else {
+        println!(
+            "{} {} {}",
+            cmd.id.to_string().yellow().bold(),
+            "-".bright_yellow().bold(),
+            highlighted
         );
-        std::process::exit(1);
+    }
+
+    let field = |label: &str, value: String| {
+        println!("  {} {}", format!("{:<12}", label).yellow().bold(), value);
+    };
+
+    if let Some(description) = cmd.description_if_set() {
+        field("Description", description.to_string());
+    }
+    if let Some(tags) = &cmd.tags {
+        field("Tags", tags.join(", "));
+    }
+    field("Collection", cmd.collection.magenta().to_string());
+    if let Some(shell) = &cmd.shell {
+        field("Shell", shell.clone());
+    }
+    if let Some(alias) = &cmd.alias {
+        field("Alias", alias.green().to_string());
+    }
+
+    let parameters = parse_parameters(&cmd.command);
+    if !parameters.is_empty() {
+        field("Parameters", String::new());
+        let width = parameters.iter().map(|p| p.name.len()).max().unwrap_or(0);
+        for param in &parameters {
+            println!(
+                "    {}  {}",
+                format!("{:<width$}", param.name).cyan().bold(),
+                describe_parameter(param).join(", ")
+            );
+        }
+    }
+
+    let when = |timestamp: Timestamp| format!("{} ({})", timestamp, timestamp.ago());
+    if let Some(created) = cmd.created_at {
+        field("Created", when(created));
+    }
+    if let Some(updated) = cmd.updated_at {
+        field("Updated", when(updated));
+    }
+    field(
+        "Runs",
+        match cmd.last_run_at {
+            Some(last) => format!("{}, last {}", cmd.run_count, when(last)),
+            None => cmd.run_count.to_string(),
+        },
+    );
+
+    if let Some(output) = &cmd.output {
+        field("Output", describe_output(output));
+        for line in &output.lines {
+            println!("    {}", line.bright_black());
+        }
     }
 
     Ok(())
 }
 
-pub fn edit_command_string(id: &u32, new_command: &String) -> Result<()> {
-    let mut shelf_data = get_shelf_data().context("Could not fetch shelf data")?;
+fn describe_output(output: &CapturedOutput) -> String {
+    format!(
+        "Captured {} with exit code {}",
+        output.captured_at.ago(),
+        output.exit_code
+    )
+}
+
+/// Help text and constraints of a parameter, for `show`.
+fn describe_parameter(param: &TemplateParam) -> Vec<String> {
+    let mut details = Vec::new();
+
+    if let Some(help) = &param.help {
+        details.push(help.clone());
+    }
+    match param.kind {
+        ParamKind::Text => {}
+        ParamKind::Int => details.push("whole number".to_string()),
+        ParamKind::Path => details.push("existing path".to_string()),
+    }
+    if param.required {
+        details.push("required".to_string());
+    }
+    if let Some(pattern) = &param.pattern {
+        details.push(format!("must match {}", pattern));
+    }
+    match &param.choices {
+        Some(Choices::List(choices)) => details.push(format!("one of {}", choices.join(", "))),
+        Some(Choices::Command(command)) => details.push(format!("picked from $({})", command)),
+        None => {}
+    }
+    if let Some(default) = &param.default {
+        details.push(format!("default {}", default));
+    }
 
-    if let Some(cmd) = shelf_data.commands.iter_mut().find(|cmd| cmd.id == *id) {
-        let old_command = cmd.command.clone();
-        cmd.command = new_command.clone();
+    if details.is_empty() {
+        details.push("no default".bright_black().to_string());
+    }
+    details
+}
 
-        ensure_data_dir_exists().context("Could not create data directory")?;
-        let toml_string =
-            toml::to_string(&shelf_data).context("Could not serialize data toml to string!")?;
-        fs::write(&get_data_path(), toml_string)
-            .context("Could not write updated data to file!")?;
+/// Prints the run history, oldest first, numbered for `shelf history rerun`.
+pub fn list_history(
+    filter: &HistoryFilter,
+    limit: &Option<usize>,
+    verbose: &bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let history = read_history()?;
+    let mut entries: Vec<(usize, &HistoryEntry)> = (1..)
+        .zip(&history)
+        .filter(|(_, entry)| filter.matches(entry))
+        .collect();
+
+    if let Some(limit) = limit {
+        let skip = entries.len().saturating_sub(*limit);
+        entries.drain(..skip);
+    }
+
+    if format != OutputFormat::Human {
+        let records: Vec<HistoryRecord> = entries
+            .into_iter()
+            .map(|(number, entry)| HistoryRecord::new(number, entry))
+            .collect();
+        return print_records(format, &records);
+    }
+
+    if entries.is_empty() {
+        println!("{}", "No runs recorded".bright_black());
+        return Ok(());
+    }
+
+    for (number, entry) in entries {
+        let status = if entry.exit_code == 0 {
+            format!("exit {}", entry.exit_code).green()
+        } else {
+            format!("exit {}", entry.exit_code).red()
+        };
 
         println!(
-            "{} {} {} {}",
-            "Updated command with id:".green(),
-            id.to_string().yellow().bold(),
-            "successfully".green(),
-            format!("({} -> {})", old_command, new_command).bright_black()
+            "{:>5}  {}  {}  {}  {}  {}",
+            number.to_string().yellow().bold(),
+            entry.started_at.to_string().bright_black(),
+            format!("#{}", entry.id).yellow(),
+            status,
+            format_duration(entry.duration()).bright_black(),
+            command_summary(&entry.command).bright_cyan().bold()
         );
-    } else {
+
+        if *verbose {
+            println!(
+                "       {} {}  {} {}",
+                "-- Cwd:".yellow().bold(),
+                entry.cwd,
+                "-- Shell:".yellow().bold(),
+                entry.shell
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the command of a history entry again, exactly as it was run, in
+/// the directory it was run in.
+pub fn rerun_history_entry(number: &usize, config: &Config) -> Result<i32> {
+    let history = read_history()?;
+    let Some(entry) = number.checked_sub(1).and_then(|index| history.get(index)) else {
         eprintln!(
-            "{}{}",
-            "Could not find saved command with id: ".red(),
-            id.to_string().yellow().bold()
+            "{} {}",
+            "Could not find history entry:".red(),
+            number.to_string().yellow().bold()
         );
-        std::process::exit(1);
+        std::process::exit(EXIT_NOT_FOUND);
+    };
+
+    let cwd = PathBuf::from(&entry.cwd);
+    if !cwd.is_dir() {
+        return Err(anyhow::anyhow!(
+            "The directory {} no longer exists",
+            entry.cwd
+        ));
     }
 
-    Ok(())
+    println!(
+        "{} {} {}",
+        "Running".green(),
+        command_summary(&entry.command).cyan().bold(),
+        format!("(in {})", entry.cwd).bright_black()
+    );
+
+    record_use(entry.id);
+    let log = RunLog::new(config);
+    let (code, _) = run_in_shell(
+        entry.id,
+        &entry.command,
+        &entry.shell,
+        Some(&cwd),
+        None,
+        &log,
+    );
+    Ok(code)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::env;
+    use std::fs;
     use std::sync::Mutex;
     use tempfile::TempDir;
 
@@ -603,6 +2095,9 @@ mod tests {
             "echo hello".to_string(),
             Some("Test command".to_string()),
             Some(vec!["test".to_string()]),
+            None,
+            None,
+            None,
         );
         assert!(result.is_ok());
 
@@ -621,6 +2116,9 @@ mod tests {
             "ssh {{user}}@{{host}}".to_string(),
             Some("SSH template".to_string()),
             None,
+            None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -640,6 +2138,9 @@ mod tests {
             "echo \\{{literal}}".to_string(),
             Some("Escaped template".to_string()),
             None,
+            None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -659,6 +2160,9 @@ mod tests {
             "echo test".to_string(),
             Some("Test".to_string()),
             Some(vec!["initial".to_string()]),
+            None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -676,13 +2180,24 @@ mod tests {
         let tags = shelf_data.commands[0].tags.as_ref().unwrap();
         assert!(tags.contains(&"newtag".to_string()));
         assert!(!tags.contains(&"initial".to_string()));
+
+        assert!(add_tag(&id, &"newtag".to_string()).is_err());
+        assert!(remove_tag(&id, &"initial".to_string()).is_err());
     }
 
     #[test]
     fn test_edit_description_and_command() {
         let _guard = setup_test_env();
 
-        save_command("echo old".to_string(), Some("Old desc".to_string()), None).unwrap();
+        save_command(
+            "echo old".to_string(),
+            Some("Old desc".to_string()),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
 
         let shelf_data = get_shelf_data().unwrap();
         let id = shelf_data.commands[0].id;
@@ -691,16 +2206,179 @@ mod tests {
         let shelf_data = get_shelf_data().unwrap();
         assert_eq!(shelf_data.commands[0].description, "New desc");
 
-        edit_command_string(&id, &"echo new".to_string()).unwrap();
+        edit_command_string(&id, "echo new").unwrap();
         let shelf_data = get_shelf_data().unwrap();
         assert_eq!(shelf_data.commands[0].command, "echo new");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_command() {
+        let guard = setup_test_env();
+        save_command(
+            "echo hello".to_string(),
+            Some("Greets".to_string()),
+            Some(vec!["old".to_string()]),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let editor = guard._temp_dir.path().join("editor.sh");
+        fs::write(
+            &editor,
+            "sed -e 's/echo hello/echo {{name}}/' -e 's/\\[\"old\"\\]/[\"new\", \"\"]/' \
+             -e 's/^collection = .*/collection = \"greetings\"/' \"$1\" > \"$1.new\"\n\
+             cat \"$1.new\" > \"$1\" && rm \"$1.new\"\n",
+        )
+        .unwrap();
+        env::set_var("VISUAL", format!("sh {}", editor.display()));
+        let result = edit_command(&1);
+        env::remove_var("VISUAL");
+        result.unwrap();
+
+        let shelf_data = get_shelf_data().unwrap();
+        let cmd = &shelf_data.commands[0];
+        assert_eq!(cmd.command, "echo {{name}}");
+        assert!(cmd.is_template);
+        assert_eq!(cmd.description, "Greets");
+        assert_eq!(cmd.tags, Some(vec!["new".to_string()]));
+        assert_eq!(cmd.collection, "greetings");
+        assert!(shelf_data.has_collection("greetings"));
+        assert_eq!(cmd.shell, None);
+
+        assert!(EditableCommand::parse("command = \"\"").is_err());
+        assert!(EditableCommand::parse("command = \"ls\"\ncolection = \"x\"").is_err());
+        assert!(EditableCommand::parse("command = \"ls\"\ncollection = \"a b\"").is_err());
+    }
+
+    #[test]
+    fn test_multi_line_commands() {
+        let guard = setup_test_env();
+        let script = guard._temp_dir.path().join("script.sh");
+        let out = guard._temp_dir.path().join("out.txt");
+        fs::write(
+            &script,
+            format!(
+                "greet() {{\n  echo \"hello $1\"\n}}\ncat <<EOF > '{}'\n$(greet {{{{name}}}})\nEOF\n\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+
+        let body = read_script(ScriptSource::File(script)).unwrap().unwrap();
+        assert!(body.ends_with("EOF"));
+        assert_eq!(command_summary(&body), "greet() { [+5 lines]");
+        assert_eq!(command_summary("ls\npwd"), "ls [+1 line]");
+        assert_eq!(command_summary("ls -la"), "ls -la");
+
+        save_command(body, None, None, None, Some("sh".to_string()), None).unwrap();
+        let provided = ProvidedValues {
+            positional: vec!["world".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            run_command(&1, &provided, &false, &Config::default()).unwrap(),
+            0
+        );
+        assert_eq!(fs::read_to_string(&out).unwrap(), "hello world\n");
+
+        let empty = guard._temp_dir.path().join("empty.sh");
+        fs::write(&empty, "\n  \n").unwrap();
+        assert!(read_script(ScriptSource::File(empty)).is_err());
+    }
+
+    #[test]
+    fn test_stack_last_escapes_braces() {
+        let guard = setup_test_env();
+        if HistoryShell::detect() == HistoryShell::Fish {
+            // fish's history file does not follow $HISTFILE
+            return;
+        }
+        let histfile = guard._temp_dir.path().join("history");
+        fs::write(&histfile, "ls\necho '{{x}}'\n").unwrap();
+        env::set_var("HISTFILE", &histfile);
+        let script = read_script(ScriptSource::ShellHistory(2));
+        env::remove_var("HISTFILE");
+
+        let script = script.unwrap().unwrap();
+        assert_eq!(script, "ls\necho '\\{{x}}'");
+        assert!(extract_parameters(&script).is_empty());
+    }
+
+    #[test]
+    fn test_import_candidates() {
+        let _guard = setup_test_env();
+        save_command("git status".to_string(), None, None, None, None, None).unwrap();
+        let shelved = get_shelf_data().unwrap().commands;
+
+        let history = ["ls", "git status", "make", "ls ", "cargo test"]
+            .map(str::to_string)
+            .to_vec();
+        assert_eq!(
+            import_candidates(history, &shelved),
+            ["cargo test", "ls", "make"]
+        );
+
+        // Braces in history are literal, and stay literal when run
+        let history = vec!["echo '{{x}}' > out.txt".to_string()];
+        let candidates = import_candidates(history, &shelved);
+        assert_eq!(candidates, ["echo '\\{{x}}' > out.txt"]);
+        assert!(extract_parameters(&candidates[0]).is_empty());
+        let imported = SavedCommand {
+            is_template: false,
+            command: candidates[0].clone(),
+            ..shelved[0].clone()
+        };
+        let prepared =
+            prepare_command(&imported, &Config::default(), &ProvidedValues::default()).unwrap();
+        assert_eq!(prepared.command, "echo '{{x}}' > out.txt");
+    }
+
+    #[test]
+    fn test_aliases() {
+        let _guard = setup_test_env();
+        let alias = |name: &str| Some(name.to_string());
+        save_command(
+            "make deploy".to_string(),
+            None,
+            None,
+            None,
+            None,
+            alias("deploy"),
+        )
+        .unwrap();
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.commands[0].alias, alias("deploy"));
+
+        // Taken by command 1, or not a valid function name
+        for name in [
+            "deploy",
+            "2fast",
+            "with space",
+            "a;b",
+            "shelf",
+            "command",
+            "__shelf_widget",
+            "shelf-widget",
+        ] {
+            let result = save_command("ls".to_string(), None, None, None, None, alias(name));
+            assert!(result.is_err(), "{}", name);
+        }
+        assert_eq!(get_shelf_data().unwrap().commands.len(), 1);
+
+        // A command may keep its own alias
+        assert!(check_alias_free("deploy", 1, &shelf_data.commands).is_ok());
+        assert!(check_alias_free("deploy", 2, &shelf_data.commands).is_err());
+        assert!(EditableCommand::parse("command = \"ls\"\nalias = \"-x\"").is_err());
+    }
+
     #[test]
     fn test_delete_command() {
         let _guard = setup_test_env();
 
-        save_command("echo test".to_string(), None, None).unwrap();
+        save_command("echo test".to_string(), None, None, None, None, None).unwrap();
         let shelf_data = get_shelf_data().unwrap();
         assert_eq!(shelf_data.commands.len(), 1);
         let id = shelf_data.commands[0].id;
@@ -709,4 +2387,487 @@ mod tests {
         let shelf_data = get_shelf_data().unwrap();
         assert_eq!(shelf_data.commands.len(), 0);
     }
+
+    #[test]
+    fn test_collections() {
+        let _guard = setup_test_env();
+
+        save_command("echo global".to_string(), None, None, None, None, None).unwrap();
+        save_command(
+            "docker ps".to_string(),
+            None,
+            None,
+            Some("docker".to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.collections, vec!["global", "docker"]);
+        assert_eq!(shelf_data.commands[0].collection, DEFAULT_COLLECTION);
+        assert_eq!(shelf_data.commands[1].collection, "docker");
+
+        rename_collection("docker", "containers").unwrap();
+        let shelf_data = get_shelf_data().unwrap();
+        assert!(!shelf_data.has_collection("docker"));
+        assert_eq!(shelf_data.commands[1].collection, "containers");
+
+        move_command(&shelf_data.commands[0].id, "containers").unwrap();
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.commands[0].collection, "containers");
+
+        delete_collection("containers", &false).unwrap();
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.collections, vec!["global"]);
+        assert_eq!(shelf_data.commands.len(), 2);
+        assert!(shelf_data
+            .commands
+            .iter()
+            .all(|cmd| cmd.collection == DEFAULT_COLLECTION));
+
+        assert!(rename_collection(DEFAULT_COLLECTION, "other").is_err());
+        assert!(delete_collection(DEFAULT_COLLECTION, &false).is_err());
+        assert!(create_collection(DEFAULT_COLLECTION).is_err());
+    }
+
+    #[test]
+    fn test_migrate_shelf_without_collections() {
+        let _guard = setup_test_env();
+
+        fs::write(
+            get_data_path(),
+            "[[commands]]\nid = 1\ncommand = \"echo old\"\n",
+        )
+        .unwrap();
+
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.collections, vec![DEFAULT_COLLECTION]);
+        assert_eq!(shelf_data.commands[0].collection, DEFAULT_COLLECTION);
+        assert_eq!(shelf_data.commands[0].created_at, None);
+        assert_eq!(shelf_data.commands[0].run_count, 0);
+    }
+
+    #[test]
+    fn test_usage_stats() {
+        let _guard = setup_test_env();
+
+        save_command(
+            "true".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let cmd = get_shelf_data().unwrap().commands[0].clone();
+        assert!(cmd.created_at.is_some());
+        assert_eq!(cmd.updated_at, cmd.created_at);
+        assert_eq!(cmd.last_run_at, None);
+
+        run_command(&1, &ProvidedValues::default(), &false, &Config::default()).unwrap();
+        run_command(&1, &ProvidedValues::default(), &false, &Config::default()).unwrap();
+        edit_description(&1, &"Does nothing".to_string()).unwrap();
+
+        let cmd = get_shelf_data().unwrap().commands[0].clone();
+        assert_eq!(cmd.run_count, 2);
+        assert!(cmd.last_run_at.is_some());
+        assert!(cmd.updated_at >= cmd.created_at);
+    }
+
+    #[test]
+    fn test_exec_command_uses_shell() {
+        let guard = setup_test_env();
+        let out = guard._temp_dir.path().join("out.txt");
+
+        save_command(
+            format!("echo 'hello  world' | tr h H > '{}'", out.display()),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let command = get_shelf_data().unwrap().commands.remove(0);
+
+        let code = exec_command(
+            command,
+            &Config::default(),
+            &ProvidedValues::default(),
+            &false,
+        )
+        .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "Hello  world\n");
+    }
+
+    #[test]
+    fn test_exec_command_exit_codes() {
+        let _guard = setup_test_env();
+
+        save_command(
+            "exit 3".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let mut command = get_shelf_data().unwrap().commands.remove(0);
+        assert_eq!(
+            exec_command(
+                command.clone(),
+                &Config::default(),
+                &ProvidedValues::default(),
+                &false
+            )
+            .unwrap(),
+            3
+        );
+
+        command.command = "kill -TERM $$".to_string();
+        assert_eq!(
+            exec_command(
+                command.clone(),
+                &Config::default(),
+                &ProvidedValues::default(),
+                &false
+            )
+            .unwrap(),
+            128 + 15
+        );
+
+        command.shell = Some("/nonexistent/shell".to_string());
+        assert_eq!(
+            exec_command(
+                command,
+                &Config::default(),
+                &ProvidedValues::default(),
+                &false
+            )
+            .unwrap(),
+            EXIT_SPAWN_FAILED
+        );
+    }
+
+    /// The env var that turns `test_concurrent_writer_process` into one of
+    /// the writers spawned by `test_concurrent_writers_do_not_lose_commands`.
+    const WRITER_ENV: &str = "SHELF_TEST_WRITER";
+
+    #[test]
+    fn test_concurrent_writer_process() {
+        let Ok(writer) = env::var(WRITER_ENV) else {
+            return;
+        };
+        for i in 0..5 {
+            save_command(
+                format!("echo {} {}", writer, i),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_writers_do_not_lose_commands() {
+        let guard = setup_test_env();
+        let data_dir = guard._temp_dir.path();
+
+        // Separate processes, so the file lock is what keeps them apart
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                std::process::Command::new(env::current_exe().unwrap())
+                    .args(["--exact", "cmd::tests::test_concurrent_writer_process"])
+                    .env(WRITER_ENV, writer.to_string())
+                    .env("SHELF_DATA_DIR", data_dir)
+                    .env("SHELF_CONFIG_DIR", data_dir)
+                    .stdout(std::process::Stdio::null())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for mut writer in writers {
+            assert!(writer.wait().unwrap().success());
+        }
+
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(shelf_data.commands.len(), 20);
+
+        let mut ids: Vec<u32> = shelf_data.commands.iter().map(|cmd| cmd.id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 20);
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let _guard = setup_test_env();
+
+        let tags = |tags: &[&str]| Some(tags.iter().map(|t| t.to_string()).collect());
+        save_command(
+            "docker ps".to_string(),
+            None,
+            tags(&["docker"]),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        save_command(
+            "docker push prod".to_string(),
+            None,
+            tags(&["docker", "prod"]),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        save_command(
+            "kubectl get pods".to_string(),
+            None,
+            tags(&["k8s"]),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let filtered = |filter: TagFilter| {
+            let mut shelf_data = get_shelf_data().unwrap();
+            filter_commands(&mut shelf_data, &None, &filter);
+            shelf_data
+                .commands
+                .iter()
+                .map(|cmd| cmd.id)
+                .collect::<Vec<_>>()
+        };
+        let args = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            filtered(TagFilter::new(&args(&["docker"]), false)),
+            vec![1, 2]
+        );
+        assert_eq!(
+            filtered(TagFilter::new(&args(&["docker", "!prod"]), false)),
+            vec![1]
+        );
+        assert_eq!(
+            filtered(TagFilter::new(&args(&["docker", "prod"]), false)),
+            vec![2]
+        );
+        assert_eq!(
+            filtered(TagFilter::new(&args(&["prod", "k8s"]), true)),
+            vec![2, 3]
+        );
+        assert_eq!(
+            filtered(TagFilter::new(&args(&["!docker"]), false)),
+            vec![3]
+        );
+
+        let shelf_data = get_shelf_data().unwrap();
+        assert_eq!(
+            count_tags(&shelf_data.commands),
+            vec![
+                ("docker".to_string(), 2),
+                ("k8s".to_string(), 1),
+                ("prod".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prepare_command_does_not_run() {
+        let guard = setup_test_env();
+        let marker = guard._temp_dir.path().join("marker file");
+
+        save_command(
+            "touch {{file}} && echo {{greeting:hi}}".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let cmd = get_shelf_data().unwrap().commands[0].clone();
+        let provided = ProvidedValues {
+            positional: vec![marker.display().to_string()],
+            ..Default::default()
+        };
+
+        // What the shell widgets insert at the prompt
+        let prepared = prepare_command(&cmd, &Config::default(), &provided).unwrap();
+        assert_eq!(
+            prepared.command,
+            format!("touch '{}' && echo hi", marker.display())
+        );
+        assert_eq!(prepared.shell, "sh");
+        assert!(!marker.exists());
+        assert!(read_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remember_values_can_be_turned_off() {
+        let _guard = setup_test_env();
+
+        let id = save_command("echo {{token}}".to_string(), None, None, None, None, None).unwrap();
+        let cmd = get_shelf_data().unwrap().commands[0].clone();
+        let provided = ProvidedValues {
+            positional: vec!["hunter2".to_string()],
+            ..Default::default()
+        };
+
+        let config = Config {
+            remember_values: Some(false),
+            ..Config::default()
+        };
+        prepare_command(&cmd, &config, &provided).unwrap();
+        assert!(ParamHistory::load().unwrap().previous(id).last.is_none());
+
+        prepare_command(&cmd, &Config::default(), &provided).unwrap();
+        let last = ParamHistory::load().unwrap().previous(id).last.unwrap();
+        assert_eq!(last["token"], "hunter2");
+    }
+
+    #[test]
+    fn test_run_history() {
+        let guard = setup_test_env();
+
+        save_command(
+            "exit {{code}}".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        for code in ["0", "3"] {
+            let provided = ProvidedValues {
+                positional: vec![code.to_string()],
+                ..Default::default()
+            };
+            run_command(&1, &provided, &false, &Config::default()).unwrap();
+        }
+
+        let history = read_history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].id, 1);
+        assert_eq!(history[1].command, "exit 3");
+        assert_eq!(history[1].exit_code, 3);
+        assert_eq!(history[1].shell, "sh");
+
+        let failed = HistoryFilter {
+            failed: true,
+            ..Default::default()
+        };
+        assert_eq!(history.iter().filter(|e| failed.matches(e)).count(), 1);
+
+        // Reruns use the recorded directory, and are logged too
+        let dir = guard._temp_dir.path().join("workdir");
+        fs::create_dir(&dir).unwrap();
+        let config = Config::default();
+        let log = RunLog::new(&config);
+        let (code, _) = run_in_shell(
+            1,
+            "test -f marker || touch marker",
+            "sh",
+            Some(&dir),
+            None,
+            &log,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(rerun_history_entry(&3, &config).unwrap(), 0);
+        assert!(dir.join("marker").exists());
+
+        let history = read_history().unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[3].cwd, dir.display().to_string());
+    }
+
+    #[test]
+    fn test_run_history_is_capped() {
+        let _guard = setup_test_env();
+
+        save_command(
+            "test {{user}} = me && test {{token}} = hunter2".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let provided = ProvidedValues {
+            positional: vec!["me".to_string(), "hunter2".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            history_size: Some(2),
+            ..Config::default()
+        };
+        for _ in 0..3 {
+            assert_eq!(run_command(&1, &provided, &false, &config).unwrap(), 0);
+        }
+
+        let history = read_history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].command, "test me = me && test hunter2 = hunter2");
+
+        let config = Config {
+            history: Some(false),
+            ..Config::default()
+        };
+        run_command(&1, &provided, &false, &config).unwrap();
+        assert_eq!(read_history().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_capture_output() {
+        let _guard = setup_test_env();
+
+        let id = save_command(
+            "seq 2; echo done >&2; seq 3 5; exit 2".to_string(),
+            None,
+            None,
+            None,
+            Some("sh".to_string()),
+            None,
+        )
+        .unwrap();
+        let config = Config {
+            output_lines: Some(6),
+            ..Default::default()
+        };
+
+        // Without --capture nothing is stored
+        run_command(&id, &ProvidedValues::default(), &false, &config).unwrap();
+        assert!(get_shelf_data().unwrap().commands[0].output.is_none());
+
+        let code = run_command(&id, &ProvidedValues::default(), &true, &config).unwrap();
+        assert_eq!(code, 2);
+
+        let output = get_shelf_data().unwrap().commands[0]
+            .output
+            .clone()
+            .unwrap();
+        assert_eq!(output.exit_code, 2);
+        // Stderr is kept too, but may land anywhere among the stdout lines
+        let lines: HashSet<&str> = output.lines.iter().map(String::as_str).collect();
+        assert_eq!(lines, HashSet::from(["1", "2", "3", "4", "5", "done"]));
+        let stdout: Vec<&str> = output
+            .lines
+            .iter()
+            .map(String::as_str)
+            .filter(|line| *line != "done")
+            .collect();
+        assert_eq!(stdout, vec!["1", "2", "3", "4", "5"]);
+    }
 }
@@ -1,4 +1,8 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
 use dirs::{config_dir, data_dir};
@@ -8,6 +12,23 @@ use serde::{Deserialize, Serialize};
 pub struct Config {
     pub storage_path: String,
     pub auto_verbose: Option<bool>,
+    /// Shell used to run saved commands. Falls back to `$SHELL`, then `sh`.
+    pub shell: Option<String>,
+    /// Rank frequently and recently used commands higher in the picker.
+    /// Enabled unless set to false.
+    pub frecency: Option<bool>,
+    /// Remember template values in params.toml. Enabled unless set to false.
+    pub remember_values: Option<bool>,
+    /// Lines of output kept by `run --capture` and `stack --run`.
+    /// Defaults to 20.
+    pub output_lines: Option<usize>,
+    /// Log runs to history.jsonl. Enabled unless set to false.
+    pub history: Option<bool>,
+    /// Runs kept in history.jsonl, oldest dropped first. Defaults to 1000.
+    pub history_size: Option<usize>,
+    /// Values for template parameters of the same name, see `shelf vars`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
 }
 
 impl Default for Config {
@@ -15,6 +36,13 @@ impl Default for Config {
         Self {
             storage_path: get_data_path().display().to_string(),
             auto_verbose: Some(false),
+            shell: None,
+            frecency: None,
+            remember_values: None,
+            output_lines: None,
+            history: None,
+            history_size: None,
+            variables: BTreeMap::new(),
         }
     }
 }
@@ -27,27 +55,38 @@ pub fn get_config_dir() -> PathBuf {
     }
 }
 
-pub fn get_config_path(config_dir: &PathBuf) -> PathBuf {
+pub fn get_config_path(config_dir: &Path) -> PathBuf {
     config_dir.join("config.toml")
 }
 
-pub fn get_data_path() -> PathBuf {
+fn get_data_dir() -> PathBuf {
     if let Ok(custom_dir) = std::env::var("SHELF_DATA_DIR") {
-        PathBuf::from(custom_dir).join("cmds.toml")
+        PathBuf::from(custom_dir)
     } else {
-        data_dir().unwrap_or_default().join("shelf/cmds.toml")
+        data_dir().unwrap_or_default().join("shelf")
     }
 }
 
-pub fn ensure_data_dir_exists() -> Result<()> {
-    let data_path = get_data_path();
-    if let Some(parent) = data_path.parent() {
-        fs::create_dir_all(parent).context("Could not create data directory")?;
-    }
-    Ok(())
+pub fn get_data_path() -> PathBuf {
+    get_data_dir().join("cmds.toml")
+}
+
+/// File holding the values recently used for each template parameter.
+pub fn get_param_history_path() -> PathBuf {
+    get_data_dir().join("params.toml")
+}
+
+/// `.shelf.toml` files the user has trusted, with the hash of their contents.
+pub fn get_trust_path() -> PathBuf {
+    get_data_dir().join("trusted.toml")
+}
+
+/// Append-only log of the commands shelf has run.
+pub fn get_history_path() -> PathBuf {
+    get_data_dir().join("history.jsonl")
 }
 
-pub fn load_config(config_dir: &PathBuf, config_path: &PathBuf) -> Result<Config> {
+pub fn load_config(config_dir: &Path, config_path: &Path) -> Result<Config> {
     // Create directories if they don't exist
     fs::create_dir_all(config_dir).context("Could not create `shelf` directory")?;
 
@@ -0,0 +1,135 @@
+//! Editing text in the user's editor, e.g. a saved command as TOML.
+
+use anyhow::{Context, Result};
+use colored::*;
+use std::{fs, path::Path, process::Command};
+
+/// Marks the lines shelf adds to explain why an edit was rejected. They are
+/// removed again before the text is read back.
+const ERROR_PREFIX: &str = "# shelf: ";
+
+/// The editor to open: `$VISUAL`, then `$EDITOR`, then a platform default.
+pub fn editor_command() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string())
+}
+
+/// The command that opens `path` in `editor`. Like git, shelf hands the
+/// editor to the shell, so it may come with arguments and quotes, e.g.
+/// `code --wait` or `'/opt/My Editor/edit'`.
+fn editor_process(editor: &str, path: &Path) -> Result<Command> {
+    if editor.trim().is_empty() {
+        return Err(anyhow::anyhow!("No editor set"));
+    }
+
+    if cfg!(windows) {
+        // Without sh, arguments are only split on whitespace
+        let mut words = editor.split_whitespace();
+        let mut command = Command::new(words.next().unwrap_or_default());
+        command.args(words).arg(path);
+        return Ok(command);
+    }
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path);
+    Ok(command)
+}
+
+/// Opens `initial` in `editor` until `parse` accepts what was saved. When
+/// it is rejected, the file is opened again with the error at the top, so
+/// no edits are lost. Returns `None` if the file was saved with nothing but
+/// comments and blank lines left in it.
+pub fn edit_until_valid<T>(
+    editor: &str,
+    initial: &str,
+    extension: &str,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<Option<T>> {
+    let file = tempfile::Builder::new()
+        .prefix("shelf-")
+        .suffix(extension)
+        .tempfile()
+        .context("Could not create a file to edit")?;
+    let path = file.path();
+    let mut content = initial.to_string();
+
+    loop {
+        fs::write(path, &content).context("Could not write the file to edit")?;
+
+        let status = editor_process(editor, path)?
+            .status()
+            .with_context(|| format!("Could not start editor {}", editor))?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "Editor exited with {}, nothing was changed",
+                status
+            ));
+        }
+
+        let edited: String = fs::read_to_string(path)
+            .context("Could not read the edited file")?
+            .lines()
+            .filter(|line| !line.starts_with(ERROR_PREFIX))
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        let is_empty = edited
+            .lines()
+            .all(|line| line.trim().is_empty() || line.trim_start().starts_with('#'));
+        if is_empty {
+            return Ok(None);
+        }
+
+        match parse(&edited) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => {
+                eprintln!("{} {:#}", "Could not apply the edit:".red(), e);
+                let error: String = format!("{:#}", e)
+                    .lines()
+                    .map(|line| format!("{}{}\n", ERROR_PREFIX, line))
+                    .collect();
+                content = error + &edited;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reopens_until_valid() {
+        let dir = TempDir::new().unwrap();
+        let editor = dir.path().join("my editor.sh");
+        // Saves an invalid number first, then fixes it once shelf complains
+        fs::write(
+            &editor,
+            "if grep -q '^# shelf: ' \"$1\"; then printf 'value = 2\\n' > \"$1\"; \
+             else echo 'value = x' >> \"$1\"; fi\n",
+        )
+        .unwrap();
+        // Quoted the way it would be in $EDITOR
+        let editor = format!("sh '{}'", editor.display());
+
+        let parse = |text: &str| -> Result<i64> {
+            let line = text.lines().find(|l| l.starts_with("value")).unwrap();
+            Ok(line.trim_start_matches("value = ").parse::<i64>()?)
+        };
+
+        let value = edit_until_valid(&editor, "# a number\n", ".toml", parse).unwrap();
+        assert_eq!(value, Some(2));
+
+        let value = edit_until_valid("true", "# nothing\n\n", ".toml", parse).unwrap();
+        assert_eq!(value, None);
+    }
+}
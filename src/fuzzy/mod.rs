@@ -8,6 +8,7 @@
 //! - Interactive selection with keyboard and mouse support.
 //! - Designed for integration into Rust-based command-line tools.
 //! - Preserves scroll position when exiting the fuzzy finder view.
+//! - Picking several items at once, marking them with Tab.
 //!
 //! ## Example
 //!
@@ -42,15 +43,44 @@ use fuzzy_matcher::FuzzyMatcher;
 use std::clone::Clone;
 use std::error::Error;
 use std::fmt::Display;
-use std::io::{stdout, Stdout, Write};
+use std::fs::OpenOptions;
+use std::io::{stderr, stdout, IsTerminal, Write};
 use std::time::Duration;
 
+/// Where to draw interactive output: stdout when it is a terminal,
+/// otherwise the controlling terminal, so the output of e.g.
+/// `$(shelf fuzz --print)` is only the selection. Falls back to stderr.
+pub fn terminal_output() -> Box<dyn Write> {
+    if stdout().is_terminal() {
+        return Box::new(stdout());
+    }
+    match OpenOptions::new().write(true).open(tty_path()) {
+        Ok(tty) => Box::new(tty),
+        Err(_) => Box::new(stderr()),
+    }
+}
+
+fn tty_path() -> &'static str {
+    if cfg!(windows) {
+        "CONOUT$"
+    } else {
+        "/dev/tty"
+    }
+}
+
 /// Struct representing a fuzzy picker for interactive item selection.
 pub struct FuzzyPicker<T: Display + Clone> {
-    stdout: Stdout,
+    stdout: Box<dyn Write>,
     matcher: SkimMatcherV2,
     items: Vec<T>,
+    boosts: Vec<i64>,
     display_items: Vec<String>,
+    /// Index into `items` of each entry of `display_items`
+    matched: Vec<usize>,
+    /// Whether Tab marks items to pick several
+    multi: bool,
+    /// Indices into `items` of the marked items
+    marked: Vec<usize>,
     num_of_items: usize,
     num_of_displayable_items: usize,
     prompt: String,
@@ -59,7 +89,9 @@ pub struct FuzzyPicker<T: Display + Clone> {
     start_index: usize,
     end_index: usize,
     height: usize,
-    initial_cursor_position: (u16, u16),
+    width: usize,
+    /// Where the cursor was before the picker opened, when it could be asked
+    initial_cursor_position: Option<(u16, u16)>,
 }
 
 impl<T: Display + Clone> FuzzyPicker<T> {
@@ -73,15 +105,19 @@ impl<T: Display + Clone> FuzzyPicker<T> {
     ///
     /// A new `FuzzyPicker` instance.
     pub fn new(items: &[T]) -> Self {
-        let (_, h) = terminal::size().unwrap();
+        let (w, h) = terminal::size().unwrap();
         let list_items = items.to_vec();
         let num_of_items = list_items.len();
         let num_of_displayable_items = num_of_items.min((h - 1) as usize);
         Self {
-            stdout: stdout(),
+            stdout: terminal_output(),
             matcher: SkimMatcherV2::default(),
             items: list_items,
+            boosts: Vec::new(),
             display_items: Vec::<String>::new(),
+            matched: Vec::new(),
+            multi: false,
+            marked: Vec::new(),
             num_of_items,
             num_of_displayable_items,
             prompt: String::new(),
@@ -90,12 +126,21 @@ impl<T: Display + Clone> FuzzyPicker<T> {
             start_index: 0,
             end_index: num_of_displayable_items.saturating_sub(1),
             height: h as usize,
-            initial_cursor_position: (0, 0),
+            width: w as usize,
+            initial_cursor_position: None,
         }
     }
 
+    /// Adds a score to each item, in the same order as the items, that is
+    /// blended into the ranking. With an empty prompt, items are listed by
+    /// boost alone; items without a boost score 0.
+    pub fn with_boosts(mut self, boosts: Vec<i64>) -> Self {
+        self.boosts = boosts;
+        self
+    }
+
     fn prev_item(&mut self) {
-        if self.num_of_items <= 0 {
+        if self.num_of_items == 0 {
             return;
         }
         if self.selected == 0 {
@@ -116,7 +161,7 @@ impl<T: Display + Clone> FuzzyPicker<T> {
     }
 
     fn next_item(&mut self) {
-        if self.num_of_items <= 0 {
+        if self.num_of_items == 0 {
             return;
         }
         self.selected = (self.selected + 1) % self.num_of_items;
@@ -145,20 +190,55 @@ impl<T: Display + Clone> FuzzyPicker<T> {
     /// `Ok(None)` if selection is cancelled,
     /// `Err(Box<dyn Error>)` for any error encountered during selection.
     pub fn pick(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        self.multi = false;
+        let picked = self.run()?;
+        Ok(picked.and_then(|indices| indices.first().map(|&index| self.items[index].clone())))
+    }
+
+    /// Like [`pick`](Self::pick), but Tab marks and unmarks items so several
+    /// can be picked at once.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(Some(items))` with the marked items in the order they were given,
+    /// or the highlighted item if none were marked,
+    /// `Ok(None)` if selection is cancelled,
+    /// `Err(Box<dyn Error>)` for any error encountered during selection.
+    pub fn pick_many(&mut self) -> Result<Option<Vec<T>>, Box<dyn Error>> {
+        self.multi = true;
+        let picked = self.run()?;
+        Ok(picked.map(|indices| {
+            indices
+                .into_iter()
+                .map(|index| self.items[index].clone())
+                .collect()
+        }))
+    }
+
+    /// Runs the picker and returns the indices of the picked items.
+    // Event handling is kept as upstream wrote it
+    #[allow(clippy::collapsible_match)]
+    fn run(&mut self) -> Result<Option<Vec<usize>>, Box<dyn Error>> {
         // Initialize state
         self.filter_by_prompt();
-        let mut picked_item: Option<T> = None;
+        self.marked.clear();
 
         // Set up terminal
         terminal::enable_raw_mode()?;
-        self.initial_cursor_position = crossterm::cursor::position()?;
+        // The terminal answers a cursor position query on stdout, so only
+        // ask when that is where the picker is drawn
+        self.initial_cursor_position = if stdout().is_terminal() {
+            Some(crossterm::cursor::position()?)
+        } else {
+            None
+        };
         self.stdout
             .queue(EnterAlternateScreen)?
             .queue(EnableMouseCapture)?
             .flush()?; // Add explicit flush
 
         // Main event loop
-        let result: Result<Option<T>, Box<dyn Error>> = (|| {
+        let result: Result<Option<Vec<usize>>, Box<dyn Error>> = (|| {
             loop {
                 if poll(Duration::from_millis(500))? {
                     match read()? {
@@ -184,21 +264,30 @@ impl<T: Display + Clone> FuzzyPicker<T> {
                                     KeyCode::Down | KeyCode::Right => {
                                         self.next_item();
                                     }
+                                    KeyCode::Tab if self.multi => {
+                                        if let Some(&index) = self.matched.get(self.selected) {
+                                            match self.marked.iter().position(|&m| m == index) {
+                                                Some(position) => {
+                                                    self.marked.remove(position);
+                                                }
+                                                None => self.marked.push(index),
+                                            }
+                                            self.next_item();
+                                        }
+                                    }
                                     KeyCode::Enter => {
+                                        if !self.marked.is_empty() {
+                                            let mut picked = self.marked.clone();
+                                            picked.sort();
+                                            return Ok(Some(picked));
+                                        }
                                         // Only try to get the selected item if we have items
                                         if !self.display_items.is_empty()
                                             && self.selected < self.display_items.len()
                                         {
-                                            picked_item = self
-                                                .items
-                                                .iter()
-                                                .find(|&item| {
-                                                    format!("{item}")
-                                                        == self.display_items[self.selected]
-                                                })
-                                                .cloned();
+                                            return Ok(Some(vec![self.matched[self.selected]]));
                                         }
-                                        return Ok(picked_item);
+                                        return Ok(None);
                                     }
                                     _ => {}
                                 }
@@ -260,12 +349,11 @@ impl<T: Display + Clone> FuzzyPicker<T> {
         self.stdout
             .queue(Clear(ClearType::All))?
             .queue(LeaveAlternateScreen)?
-            .queue(DisableMouseCapture)?
-            .queue(MoveTo(
-                self.initial_cursor_position.0,
-                self.initial_cursor_position.1,
-            ))?
-            .flush()?;
+            .queue(DisableMouseCapture)?;
+        if let Some((column, row)) = self.initial_cursor_position {
+            self.stdout.queue(MoveTo(column, row))?;
+        }
+        self.stdout.flush()?;
 
         terminal::disable_raw_mode()?;
 
@@ -273,31 +361,33 @@ impl<T: Display + Clone> FuzzyPicker<T> {
     }
 
     fn filter_by_prompt(&mut self) {
-        self.display_items = self
+        let prompt = self.prompt.to_lowercase();
+
+        let mut scored: Vec<(i64, usize)> = self
             .items
             .iter()
-            .filter_map(|item| {
-                let display_str = format!("{}", item);
-                if self.prompt.is_empty()
-                    || self
-                        .matcher
-                        .fuzzy_match(&display_str.to_lowercase(), &self.prompt.to_lowercase())
-                        .unwrap_or_default()
-                        != 0
-                {
-                    Some(display_str)
-                } else {
-                    None
+            .enumerate()
+            .filter_map(|(index, item)| {
+                let boost = self.boosts.get(index).copied().unwrap_or_default();
+                if prompt.is_empty() {
+                    return Some((boost, index));
                 }
+                self.matcher
+                    .fuzzy_match(&format!("{}", item).to_lowercase(), &prompt)
+                    .filter(|&score| score != 0)
+                    .map(|score| (score + boost, index))
             })
             .collect();
 
-        self.display_items.sort_by_key(|item| {
-            -self
-                .matcher
-                .fuzzy_match(&item.to_lowercase(), &self.prompt.to_lowercase())
-                .unwrap_or_default()
-        });
+        // Stable, so equal scores keep the order the items were given in
+        scored.sort_by_key(|&(score, _)| -score);
+        self.matched = scored.into_iter().map(|(_, index)| index).collect();
+        self.display_items = self
+            .matched
+            .iter()
+            .map(|&index| format!("{}", self.items[index]))
+            .collect();
+
         self.num_of_items = self.display_items.len();
         self.num_of_displayable_items = self.num_of_items.min(self.height - 1);
         if self.num_of_displayable_items == 0 {
@@ -307,6 +397,8 @@ impl<T: Display + Clone> FuzzyPicker<T> {
         }
     }
 
+    // Drawing is kept as upstream wrote it
+    #[allow(clippy::useless_format, clippy::explicit_counter_loop)]
     fn render_frame(&mut self) -> Result<(), Box<dyn Error>> {
         let prompt_styled = format!("> {}", self.prompt).green().bold();
         let debug_info = format!("{}", self.debug).red().bold();
@@ -322,6 +414,13 @@ impl<T: Display + Clone> FuzzyPicker<T> {
                 .queue(PrintStyledContent(debug_info))?;
         }
 
+        if self.multi {
+            let hint = format!("{} marked, Tab to mark, Enter to pick", self.marked.len());
+            self.stdout
+                .queue(MoveTo(self.width.saturating_sub(hint.len()) as u16, 0))?
+                .queue(PrintStyledContent(hint.dark_grey()))?;
+        }
+
         let mut row = 1;
         for (index, item) in self
             .display_items
@@ -330,9 +429,14 @@ impl<T: Display + Clone> FuzzyPicker<T> {
             .skip(self.start_index)
             .take(self.num_of_displayable_items)
         {
+            let gutter = if self.marked.contains(&self.matched[index]) {
+                "+"
+            } else {
+                " "
+            };
             self.stdout
                 .queue(MoveTo(0, row))?
-                .queue(PrintStyledContent(" ".on_dark_grey()))?;
+                .queue(PrintStyledContent(gutter.on_dark_grey()))?;
 
             if index == self.selected {
                 self.stdout
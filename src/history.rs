@@ -0,0 +1,129 @@
+//! Run history: one JSON line per command shelf executed, appended to
+//! `history.jsonl` in the data directory so it can be audited later. Only
+//! the most recent entries are kept.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    time::Duration,
+};
+
+use crate::{
+    config::get_history_path,
+    storage::{with_lock, write_atomic},
+    timestamp::Timestamp,
+};
+
+/// Number of entries kept unless configured otherwise.
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub started_at: Timestamp,
+    /// Id of the saved command that was run
+    pub id: u32,
+    /// The command as executed, with template values filled in
+    pub command: String,
+    pub cwd: String,
+    pub shell: String,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+}
+
+impl HistoryEntry {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+/// Which entries `shelf history` shows.
+#[derive(Default)]
+pub struct HistoryFilter {
+    pub id: Option<u32>,
+    pub status: Option<i32>,
+    pub failed: bool,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.id.is_none_or(|id| entry.id == id)
+            && self.status.is_none_or(|status| entry.exit_code == status)
+            && (!self.failed || entry.exit_code != 0)
+    }
+}
+
+/// Appends an entry, then drops the oldest entries beyond `max_entries`.
+/// Both happen under the history's lock, so entries from concurrent shelf
+/// processes are neither interleaved nor lost.
+pub fn append_entry(entry: &HistoryEntry, max_entries: usize) -> Result<()> {
+    let path = get_history_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Could not create data directory")?;
+    }
+
+    let mut line = serde_json::to_string(entry).context("Could not serialize history entry")?;
+    line.push('\n');
+
+    with_lock(&path, || {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Could not write to {}", path.display()))?;
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() <= max_entries {
+            return Ok(());
+        }
+        let kept: String = lines[lines.len() - max_entries..]
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+        write_atomic(&path, kept.as_bytes())
+    })
+}
+
+/// All entries, oldest first. Entry numbers used by `shelf history rerun`
+/// are positions in this list, starting at 1. Lines that cannot be parsed,
+/// e.g. from an interrupted write, are skipped.
+pub fn read_history() -> Result<Vec<HistoryEntry>> {
+    let path = get_history_path();
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Short human readable duration, e.g. `450ms`, `3.2s` or `4m05s`.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    match millis {
+        m if m < 1000 => format!("{}ms", m),
+        m if m < 60_000 => format!("{:.1}s", m as f64 / 1000.0),
+        m => format!("{}m{:02}s", m / 60_000, m % 60_000 / 1000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(450)), "450ms");
+        assert_eq!(format_duration(Duration::from_millis(3_240)), "3.2s");
+        assert_eq!(format_duration(Duration::from_millis(245_000)), "4m05s");
+    }
+}
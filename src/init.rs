@@ -0,0 +1,187 @@
+//! Shell code printed by `shelf init`: a key binding that opens the picker
+//! and inserts the picked command at the prompt, ready to edit, and an
+//! alias or function for every command with an alias.
+
+use crate::{cmd::SavedCommand, shell_history::HistoryShell, template::extract_parameters};
+
+/// Names the code from `shelf init` defines or relies on, which an alias
+/// would overwrite or hide.
+pub const RESERVED_NAMES: &[&str] = &["shelf", "command", "__shelf_widget", "shelf-widget"];
+
+/// Builtins of bash, zsh or fish that an alias would hide.
+pub const SHELL_BUILTINS: &[&str] = &[
+    "alias", "bg", "bind", "builtin", "cd", "echo", "eval", "exec", "exit", "export", "false",
+    "fg", "function", "history", "jobs", "kill", "local", "printf", "pwd", "read", "return", "set",
+    "source", "test", "trap", "true", "type", "ulimit", "umask", "unalias", "unset", "wait",
+];
+
+const BASH_WIDGET: &str = r#"# shelf: Ctrl-G inserts a shelved command at the prompt
+__shelf_widget() {
+    local selected
+    selected="$(command shelf fuzz --print < /dev/tty)" || return
+    READLINE_LINE="${READLINE_LINE:0:READLINE_POINT}${selected}${READLINE_LINE:READLINE_POINT}"
+    READLINE_POINT=$((READLINE_POINT + ${#selected}))
+}
+
+if [[ $- == *i* ]]; then
+    bind -m emacs-standard -x '"\C-g": __shelf_widget'
+    bind -m vi-insert -x '"\C-g": __shelf_widget'
+fi
+"#;
+
+const ZSH_WIDGET: &str = r#"# shelf: Ctrl-G inserts a shelved command at the prompt
+shelf-widget() {
+    local selected
+    selected="$(command shelf fuzz --print < /dev/tty)"
+    if [[ -n $selected ]]; then
+        LBUFFER+="$selected"
+    fi
+    zle reset-prompt
+}
+
+zle -N shelf-widget
+bindkey -M emacs '^G' shelf-widget
+bindkey -M viins '^G' shelf-widget
+"#;
+
+const FISH_WIDGET: &str = r#"# shelf: Ctrl-G inserts a shelved command at the prompt
+function __shelf_widget
+    set -l selected (command shelf fuzz --print < /dev/tty | string collect)
+    if test -n "$selected"
+        commandline --insert -- $selected
+    end
+    commandline -f repaint
+end
+
+bind \cg __shelf_widget
+if bind -M insert >/dev/null 2>&1
+    bind -M insert \cg __shelf_widget
+end
+"#;
+
+/// The code to evaluate in the shell's startup file, e.g.
+/// `eval "$(shelf init bash)"` in `~/.bashrc`. `commands` are the commands
+/// to define aliases for; their aliases must be valid.
+pub fn init_script(shell: HistoryShell, commands: &[SavedCommand]) -> String {
+    let mut script = match shell {
+        HistoryShell::Bash => BASH_WIDGET,
+        HistoryShell::Zsh => ZSH_WIDGET,
+        HistoryShell::Fish => FISH_WIDGET,
+    }
+    .to_string();
+
+    let aliased: Vec<(&str, &SavedCommand)> = commands
+        .iter()
+        .filter_map(|cmd| Some((cmd.alias.as_deref()?, cmd)))
+        .collect();
+    if !aliased.is_empty() {
+        script.push_str("\n# shelf: aliases of shelved commands\n");
+    }
+    for (alias, cmd) in aliased {
+        script.push_str(&alias_definition(shell, alias, cmd));
+    }
+    script
+}
+
+/// Runs `cmd` by id. Templates become functions that pass their arguments
+/// on as positional template values, so `deploy staging` fills in the
+/// first placeholder of the command with `staging`.
+fn alias_definition(shell: HistoryShell, alias: &str, cmd: &SavedCommand) -> String {
+    let parameters = extract_parameters(&cmd.command);
+    let usage: String = parameters
+        .iter()
+        .map(|name| format!(" <{}>", name))
+        .collect();
+    let mut definition = format!("# {}{}: ", alias, usage);
+    match cmd.description_if_set().and_then(|d| d.lines().next()) {
+        Some(description) => definition.push_str(description),
+        None => definition.push_str(&format!("command {}", cmd.id)),
+    }
+    definition.push('\n');
+
+    definition.push_str(&match (shell, parameters.is_empty()) {
+        (HistoryShell::Fish, true) => format!(
+            "function {}\n    command shelf run {}\nend\n",
+            alias, cmd.id
+        ),
+        (HistoryShell::Fish, false) => format!(
+            "function {}\n    command shelf run {} -- $argv\nend\n",
+            alias, cmd.id
+        ),
+        (_, true) => format!("alias {}='command shelf run {}'\n", alias, cmd.id),
+        // An alias of the same name would be expanded instead of the function
+        (_, false) => format!(
+            "unalias {0} 2>/dev/null\nfunction {0} {{\n    command shelf run {1} -- \"$@\"\n}}\n",
+            alias, cmd.id
+        ),
+    });
+    definition
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn command(id: u32, command: &str, alias: Option<&str>) -> SavedCommand {
+        let text = format!(
+            "id = {}\ncommand = '{}'\ndescription = 'Deploy'",
+            id, command
+        );
+        SavedCommand {
+            alias: alias.map(str::to_string),
+            ..toml::from_str(&text).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_alias_definitions() {
+        let commands = [
+            command(1, "./deploy.sh {{env}} {{version:latest}}", Some("deploy")),
+            command(2, "git status", Some("gs")),
+            command(3, "ls", None),
+        ];
+
+        let bash = init_script(HistoryShell::Bash, &commands);
+        assert!(bash.contains(
+            "# deploy <env> <version>: Deploy\nunalias deploy 2>/dev/null\n\
+             function deploy {\n    command shelf run 1 -- \"$@\"\n}\n"
+        ));
+        assert!(bash.contains("alias gs='command shelf run 2'\n"));
+        assert!(!bash.contains("run 3"));
+
+        let fish = init_script(HistoryShell::Fish, &commands);
+        assert!(fish.contains("function deploy\n    command shelf run 1 -- $argv\nend\n"));
+        assert!(fish.contains("function gs\n    command shelf run 2\nend\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scripts_parse() {
+        let commands = [
+            command(1, "echo {{name}}", Some("greet")),
+            command(2, "ls", Some("l")),
+        ];
+        for (shell, program, check) in [
+            (HistoryShell::Bash, "bash", "-n"),
+            (HistoryShell::Zsh, "zsh", "-n"),
+            (HistoryShell::Fish, "fish", "--no-execute"),
+        ] {
+            let script = init_script(shell, &commands);
+            // Only checked where the shell is installed
+            let Ok(output) = Command::new(program).args([check, "-c", &script]).output() else {
+                eprintln!(
+                    "skipping the {} init script: {} is not installed",
+                    program, program
+                );
+                continue;
+            };
+            assert!(
+                output.status.success(),
+                "{}: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
+}
@@ -1,16 +1,43 @@
+mod capture;
 mod cmd;
 mod config;
+mod editor;
 mod fuzzy;
+mod history;
+mod init;
+mod output;
+mod param_history;
+mod prompt;
+mod search;
+mod shell;
+mod shell_history;
+mod storage;
+mod template;
+mod timestamp;
+mod variables;
 
 use anyhow::{Context, Result};
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{builder::RangedU64ValueParser, ArgGroup, CommandFactory, Parser, Subcommand};
 use clap_complete::{generate, Generator, Shell};
 use cmd::{
-    add_tag, copy_command, delete_command, edit_command_string, edit_description, fuzzy_search,
-    list_commands, remove_tag, run_command, save_command,
+    add_tag, copy_command, create_collection, delete_collection, delete_command, edit_command,
+    edit_command_string, edit_description, fuzzy_search, import_history, list_collections,
+    list_commands, list_history, list_tags, list_variables, move_command, print_init_script,
+    prompt_details, read_script, remove_tag, rename_collection, rerun_history_entry, run_command,
+    save_command, search_commands, set_variable, show_command, trust_variables, unset_variable,
+    ScriptSource, TagFilter,
 };
+use colored::*;
 use config::{get_config_dir, get_config_path, load_config};
-use std::io;
+use history::HistoryFilter;
+use output::{print_record, ConfigRecord, OutputFormat};
+use search::{FirstField, MatchMode, SortOrder};
+use shell_history::HistoryShell;
+use std::{
+    io::{self, IsTerminal},
+    path::PathBuf,
+};
+use template::{parse_set_value, ProvidedValues};
 
 #[derive(Parser)]
 #[command(
@@ -28,6 +55,11 @@ struct ShelfCli {
     /// Subcommand to run e.g save
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Output format for list, search, show, tags, collection, vars, history
+    /// and config
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
 }
 
 #[derive(Subcommand)]
@@ -42,7 +74,8 @@ enum Commands {
     ///   shelf stack -d "SSH to server" ssh {{user}}@{{host}}
     ///   shelf stack -t docker,run docker run -it {{image}} {{command}}
     ///   shelf stack 'echo \{{literal}}' # Saves literal {{literal}}
-    #[command(alias = "save")]
+    ///   shelf stack -d "Backup script" --from-file backup.sh
+    #[command(alias = "save", group(ArgGroup::new("source").required(true)))]
     Stack {
         /// Description of the command (optional)
         #[arg(short, long, required = false)]
@@ -52,8 +85,50 @@ enum Commands {
         #[arg(short, long, allow_hyphen_values = true)]
         tags: Option<String>,
 
+        /// Collection to stack the command to (default: global)
+        #[arg(short = 'C', long)]
+        collection: Option<String>,
+
+        /// Shell to run this command with, e.g bash, zsh, fish or sh
+        /// (default: `shell` from config, then $SHELL)
+        #[arg(short, long)]
+        shell: Option<String>,
+
+        /// Name to run the command by in your shell, defined by `shelf init`
+        #[arg(short, long)]
+        alias: Option<String>,
+
+        /// Run the command once after saving it, and store the end of its
+        /// output with it
+        #[arg(short, long)]
+        run: bool,
+
+        /// Read a multi-line command from a file
+        #[arg(long, value_name = "PATH", group = "source")]
+        from_file: Option<PathBuf>,
+
+        /// Read a multi-line command from stdin
+        #[arg(long, group = "source")]
+        stdin: bool,
+
+        /// Write a multi-line command in $VISUAL or $EDITOR
+        #[arg(long, group = "source")]
+        editor: bool,
+
+        /// Stack the last command from your shell history, or the last N
+        /// as one multi-line command. Asks for a description and tags.
+        #[arg(
+            long,
+            value_name = "N",
+            num_args = 0..=1,
+            default_missing_value = "1",
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..),
+            group = "source"
+        )]
+        last: Option<usize>,
+
         /// The command to save. Use {{param}} for template parameters.
-        #[arg(required = true, allow_hyphen_values = true, trailing_var_arg = true)]
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true, group = "source")]
         command: Vec<String>,
     },
     /// List saved commands
@@ -67,13 +142,51 @@ enum Commands {
         /// Limit the order of the listed commands.
         #[arg(short, long)]
         limit: Option<u32>,
+        /// Only list commands in this collection
+        #[arg(short = 'C', long)]
+        collection: Option<String>,
+        /// Only list commands with this tag. Repeatable; prefix with ! to
+        /// exclude a tag, e.g -t docker -t '!prod'
+        #[arg(short = 't', long = "tag", allow_hyphen_values = true)]
+        tags: Vec<String>,
+        /// Match commands with any of the given tags instead of all of them
+        #[arg(long, required = false)]
+        any: bool,
+        /// Order to list commands in
+        #[arg(short, long, value_enum, default_value_t = SortOrder::Id)]
+        sort: SortOrder,
     },
     /// Run a command via an id
+    ///
+    /// Template values can be given instead of being prompted for:
+    ///   shelf run 5 --set user=root --set host=db1
+    ///   shelf run 5 -- root db1
     Run {
         /// Also copy the command to clipboard after running
         #[arg(short, long, required = false)]
         copy: bool,
+        /// Set a template parameter, e.g --set user=root. Repeatable.
+        #[arg(short, long = "set", value_name = "NAME=VALUE", value_parser = parse_set_value)]
+        set: Vec<(String, String)>,
+        /// Reuse the template values of the previous run
+        #[arg(long, conflicts_with = "values")]
+        last: bool,
+        /// Store the last lines of output with the command, while still
+        /// printing all of it
+        #[arg(long)]
+        capture: bool,
         id: u32,
+        /// Template values in parameter order, after --
+        #[arg(last = true)]
+        values: Vec<String>,
+    },
+    /// Show a saved command in full: its parameters, usage and the output
+    /// of its last captured run
+    Show {
+        id: u32,
+        /// Print the output stored by the last `run --capture` instead
+        #[arg(short, long)]
+        output: bool,
     },
     /// Copy a command to clipboard by ID
     #[command(alias = "c")]
@@ -84,6 +197,65 @@ enum Commands {
         /// Copy a selected command rather than run
         #[arg(short, long, required = false)]
         copy: bool,
+        /// Print the selected command with its template values filled in
+        /// rather than run it. The picker is drawn on the terminal, so
+        /// only the command is written to stdout.
+        #[arg(short, long, conflicts_with = "copy")]
+        print: bool,
+        /// Only search commands in this collection
+        #[arg(short = 'C', long)]
+        collection: Option<String>,
+        /// Only search commands with this tag. Repeatable; prefix with ! to
+        /// exclude a tag, e.g -t docker -t '!prod'
+        #[arg(short = 't', long = "tag", allow_hyphen_values = true)]
+        tags: Vec<String>,
+        /// Match commands with any of the given tags instead of all of them
+        #[arg(long, required = false)]
+        any: bool,
+    },
+    /// Search commands without the interactive picker, printing the
+    /// best matches first. Exits with 1 when nothing matches.
+    ///
+    /// Qualifiers narrow a search to one field:
+    ///   tag:docker  tag:!prod  desc:backup  cmd:rsync  col:work
+    ///   id:7  id:>10  id:<=3  id:5..9
+    ///
+    /// Examples:
+    ///   shelf search docker tag:!prod
+    ///   shelf search --exact cmd:rsync desc:backup
+    ///   shelf run $(shelf search --first ssh)
+    #[command(alias = "s")]
+    Search {
+        /// Words and field qualifiers to search for
+        #[arg(required = true, allow_hyphen_values = true, trailing_var_arg = true)]
+        query: Vec<String>,
+        /// Match words as case-insensitive substrings instead of fuzzily
+        #[arg(short, long, conflicts_with = "regex")]
+        exact: bool,
+        /// Treat words as regular expressions
+        #[arg(short = 'x', long)]
+        regex: bool,
+        /// Only print the best match's id (default) or command
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "id"
+        )]
+        first: Option<FirstField>,
+        /// Display tags, description and collection of each match
+        #[arg(short, long, required = false)]
+        verbose: bool,
+        /// Limit the number of printed matches
+        #[arg(short, long)]
+        limit: Option<u32>,
+    },
+    /// List all tags and how many commands use them
+    Tags {
+        /// Only count commands in this collection
+        #[arg(short = 'C', long)]
+        collection: Option<String>,
     },
     /// Delete a saved command by ID
     #[command(name = "delete", alias = "del")]
@@ -92,6 +264,8 @@ enum Commands {
     Rmtag { id: u32, tag: String },
     /// Add a tag to a saved command
     Addtag { id: u32, tag: String },
+    /// Edit a saved command in $VISUAL or $EDITOR
+    Edit { id: u32 },
     /// Edit the description of a saved command
     #[command(name = "editdesc", alias = "edesc")]
     EditDesc { id: u32, description: String },
@@ -102,16 +276,150 @@ enum Commands {
         #[arg(required = true, allow_hyphen_values = true, trailing_var_arg = true)]
         command: Vec<String>,
     },
+    /// Move a saved command to another collection
+    #[command(alias = "mv")]
+    Move { id: u32, collection: String },
+    /// Manage collections. Lists them when no action is given.
+    #[command(alias = "col")]
+    Collection {
+        #[command(subcommand)]
+        action: Option<CollectionAction>,
+    },
+    /// Stack many commands at once
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    /// Show or rerun commands shelf has run. Lists them when no action is
+    /// given.
+    #[command(alias = "h")]
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+    },
+    /// Manage template variables. Lists them when no action is given.
+    #[command(alias = "var")]
+    Vars {
+        #[command(subcommand)]
+        action: Option<VarsAction>,
+    },
     Completion {
         #[arg(value_enum)]
         shell: Shell,
     },
+    /// Print shell code that binds Ctrl-G to the picker and inserts the
+    /// picked command at the prompt, and defines an alias or function for
+    /// every command stacked with --alias, e.g. eval "$(shelf init bash)"
+    Init {
+        /// Shell to print the code for (default: the one in $SHELL)
+        #[arg(value_enum)]
+        shell: Option<HistoryShell>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CollectionAction {
+    /// List collections and how many commands they hold
+    #[command(alias = "ls")]
+    List,
+    /// Create an empty collection
+    #[command(alias = "new")]
+    Create { name: String },
+    /// Rename a collection, keeping its commands
+    Rename { name: String, new_name: String },
+    /// Delete a collection. Its commands are moved to the global collection.
+    #[command(alias = "del")]
+    Delete {
+        name: String,
+        /// Delete the collection's commands instead of moving them
+        #[arg(long)]
+        purge: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImportSource {
+    /// Pick commands from your shell history, marking several with Tab
+    History {
+        /// Shell whose history to read (default: the one in $SHELL)
+        #[arg(short, long, value_enum)]
+        shell: Option<HistoryShell>,
+        /// Comma seperated tags to add to every imported command
+        #[arg(short, long, allow_hyphen_values = true)]
+        tags: Option<String>,
+        /// Also tag each command with the program it runs, e.g git or docker
+        #[arg(long)]
+        tag_by_program: bool,
+        /// Collection to stack the commands to (default: global)
+        #[arg(short = 'C', long)]
+        collection: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// List runs, oldest first
+    #[command(alias = "ls")]
+    List {
+        /// Only runs of the command with this id
+        #[arg(long)]
+        id: Option<u32>,
+        /// Only runs that exited with this status
+        #[arg(long, allow_hyphen_values = true)]
+        status: Option<i32>,
+        /// Only runs that failed
+        #[arg(short, long, conflicts_with = "status")]
+        failed: bool,
+        /// Only the last N matching runs
+        #[arg(short, long)]
+        limit: Option<usize>,
+        /// Also show the working directory and shell of each run
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Run a history entry again, in the directory it was run in
+    Rerun { entry: usize },
+}
+
+#[derive(Subcommand)]
+enum VarsAction {
+    /// List variables, their values and where they are set
+    #[command(alias = "ls")]
+    List,
+    /// Set a variable in config.toml
+    Set {
+        name: String,
+        value: String,
+        /// Set it in the nearest .shelf.toml instead, creating one in the
+        /// current directory if there is none
+        #[arg(short, long)]
+        local: bool,
+    },
+    /// Remove a variable from config.toml
+    #[command(alias = "rm")]
+    Unset {
+        name: String,
+        /// Remove it from the nearest .shelf.toml instead
+        #[arg(short, long)]
+        local: bool,
+    },
+    /// Use the variables of the nearest .shelf.toml. Needed again whenever
+    /// someone else changes the file
+    Trust,
 }
 
 fn print_completions<G: Generator>(gen: G, cmd: &mut clap::Command) {
     generate(gen, cmd, "shelf".to_string(), &mut io::stdout());
 }
 
+/// Exits with `code` unless it signals success, so `shelf run` and
+/// `shelf fuzz` report the saved command's status to the caller.
+fn exit_with(code: i32) {
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
 fn main() -> Result<()> {
     let config_dir = get_config_dir();
     let config_path = get_config_path(&config_dir);
@@ -121,47 +429,166 @@ fn main() -> Result<()> {
 
     match &cli.command {
         Some(Commands::Config) => {
-            println!("{:?} is the config dir", config_dir);
-            println!("{:?} is the config path", config_path);
-            println!("{:?} is the storage path", config.storage_path);
+            if cli.format != OutputFormat::Human {
+                let record = ConfigRecord {
+                    config_dir: config_dir.display().to_string(),
+                    config_path: config_path.display().to_string(),
+                    storage_path: config.storage_path.clone(),
+                };
+                return print_record(cli.format, &record);
+            }
+
+            println!("{} is the config dir", config_dir.display());
+            println!("{} is the config path", config_path.display());
+            println!("{} is the storage path", config.storage_path);
         }
         Some(Commands::Stack {
             description,
             command,
             tags,
-        }) => save_command(
-            command.join(" "),
-            description.clone(),
-            if let Some(tags) = tags {
-                Some(tags.split(",").map(|s| s.to_string()).collect())
-            } else {
-                None
-            },
-        )?,
+            collection,
+            shell,
+            alias,
+            run,
+            from_file,
+            stdin,
+            editor,
+            last,
+        }) => {
+            let source = match (from_file, stdin, editor, last) {
+                (Some(path), _, _, _) => Some(ScriptSource::File(path.clone())),
+                (_, true, _, _) => Some(ScriptSource::Stdin),
+                (_, _, true, _) => Some(ScriptSource::Editor),
+                (_, _, _, Some(count)) => Some(ScriptSource::ShellHistory(*count)),
+                _ => None,
+            };
+            let mut description = description.clone();
+            let mut tags = tags
+                .as_ref()
+                .map(|tags| tags.split(",").map(|s| s.to_string()).collect());
+
+            let command = match source {
+                Some(source) => match read_script(source)? {
+                    Some(script) => script,
+                    None => return Ok(()),
+                },
+                None => command.join(" "),
+            };
+
+            if last.is_some()
+                && io::stdin().is_terminal()
+                && !prompt_details(&mut description, &mut tags)?
+            {
+                println!("{}", "Nothing was stacked".yellow());
+                return Ok(());
+            }
+
+            let id = save_command(
+                command,
+                description,
+                tags,
+                collection.clone(),
+                shell.clone(),
+                alias.clone(),
+            )?;
+
+            if *run {
+                exit_with(run_command(
+                    &id,
+                    &ProvidedValues::default(),
+                    &true,
+                    &config,
+                )?);
+            }
+        }
         Some(Commands::List {
             verbose,
             reverse,
             limit,
+            collection,
+            tags,
+            any,
+            sort,
         }) => {
             list_commands(
                 &(config.auto_verbose.unwrap_or(false) || *verbose),
                 reverse,
                 limit,
+                collection,
+                &TagFilter::new(tags, *any),
+                *sort,
+                cli.format,
             )?;
         }
-        Some(Commands::Run { id, copy }) => {
-            let result = run_command(id);
+        Some(Commands::Run {
+            id,
+            copy,
+            set,
+            last,
+            capture,
+            values,
+        }) => {
+            let provided = ProvidedValues {
+                named: set.clone(),
+                positional: values.clone(),
+                last: *last,
+            };
+            let result = run_command(id, &provided, capture, &config);
 
             if *copy {
-                let _ = copy_command(id);
+                let _ = copy_command(id, false);
             }
 
-            return result;
+            exit_with(result?);
         }
+        Some(Commands::Show { id, output }) => show_command(id, output, cli.format)?,
         Some(Commands::Copy { id }) => {
-            return copy_command(id);
+            return copy_command(id, true);
+        }
+        Some(Commands::Fuzz {
+            copy,
+            print,
+            collection,
+            tags,
+            any,
+        }) => {
+            exit_with(fuzzy_search(
+                copy,
+                print,
+                collection,
+                &TagFilter::new(tags, *any),
+                &config,
+            )?);
+        }
+        Some(Commands::Search {
+            query,
+            exact,
+            regex,
+            first,
+            verbose,
+            limit,
+        }) => {
+            let mode = if *regex {
+                MatchMode::Regex
+            } else if *exact {
+                MatchMode::Exact
+            } else {
+                MatchMode::Fuzzy
+            };
+
+            let found = search_commands(
+                &query.join(" "),
+                mode,
+                first,
+                &(config.auto_verbose.unwrap_or(false) || *verbose),
+                limit,
+                cli.format,
+            )?;
+            exit_with(if found { 0 } else { 1 });
+        }
+        Some(Commands::Tags { collection }) => {
+            list_tags(collection, cli.format)?;
         }
-        Some(Commands::Fuzz { copy }) => return fuzzy_search(copy),
         Some(Commands::Delete { id }) => {
             delete_command(id)?;
         }
@@ -171,16 +598,71 @@ fn main() -> Result<()> {
         Some(Commands::Addtag { id, tag }) => {
             add_tag(id, tag)?;
         }
+        Some(Commands::Edit { id }) => {
+            edit_command(id)?;
+        }
         Some(Commands::EditDesc { id, description }) => {
             edit_description(id, description)?;
         }
         Some(Commands::EditCommand { id, command }) => {
             edit_command_string(id, &command.join(" "))?;
         }
+        Some(Commands::Move { id, collection }) => {
+            move_command(id, collection)?;
+        }
+        Some(Commands::Collection { action }) => match action {
+            None | Some(CollectionAction::List) => list_collections(cli.format)?,
+            Some(CollectionAction::Create { name }) => create_collection(name)?,
+            Some(CollectionAction::Rename { name, new_name }) => rename_collection(name, new_name)?,
+            Some(CollectionAction::Delete { name, purge }) => delete_collection(name, purge)?,
+        },
+        Some(Commands::Import { source }) => match source {
+            ImportSource::History {
+                shell,
+                tags,
+                tag_by_program,
+                collection,
+            } => import_history(
+                *shell,
+                tags.as_ref()
+                    .map(|tags| tags.split(",").map(|s| s.to_string()).collect()),
+                tag_by_program,
+                collection.clone(),
+            )?,
+        },
+        Some(Commands::History { action }) => match action {
+            None => list_history(&HistoryFilter::default(), &None, &false, cli.format)?,
+            Some(HistoryAction::List {
+                id,
+                status,
+                failed,
+                limit,
+                verbose,
+            }) => {
+                let filter = HistoryFilter {
+                    id: *id,
+                    status: *status,
+                    failed: *failed,
+                };
+                list_history(&filter, limit, verbose, cli.format)?
+            }
+            Some(HistoryAction::Rerun { entry }) => exit_with(rerun_history_entry(entry, &config)?),
+        },
+        Some(Commands::Vars { action }) => match action {
+            None | Some(VarsAction::List) => list_variables(&config, cli.format)?,
+            Some(VarsAction::Set { name, value, local }) => {
+                set_variable(name, value, local, &config_path)?
+            }
+            Some(VarsAction::Unset { name, local }) => unset_variable(name, local, &config_path)?,
+            Some(VarsAction::Trust) => trust_variables()?,
+        },
         Some(Commands::Completion { shell }) => {
             let mut cmd = ShelfCli::command();
             print_completions(*shell, &mut cmd);
         }
+        Some(Commands::Init { shell }) => {
+            print_init_script(shell.unwrap_or_else(HistoryShell::detect))?;
+        }
         None => {}
     }
 
@@ -0,0 +1,447 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    capture::CapturedOutput,
+    cmd::SavedCommand,
+    history::HistoryEntry,
+    template::{extract_parameters, parse_parameters, Choices, ParamKind, TemplateParam},
+    timestamp::Timestamp,
+};
+
+/// Output format selected with the global `--format` option.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Colored text for people
+    #[default]
+    Human,
+    /// A single JSON document (an array for lists)
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// Tab separated values with a header row
+    Tsv,
+}
+
+/// A structure that can be printed in the machine-readable formats. Field
+/// names and order are part of shelf's output contract, see the README.
+pub trait Record: Serialize {
+    const TSV_HEADER: &'static [&'static str];
+
+    fn tsv_fields(&self) -> Vec<String>;
+}
+
+/// Prints a list of records. Does nothing for `OutputFormat::Human`, which
+/// callers render themselves.
+pub fn print_records<R: Record>(format: OutputFormat, records: &[R]) -> Result<()> {
+    match format {
+        OutputFormat::Human => {}
+        OutputFormat::Json => println!("{}", to_json_pretty(records)?),
+        OutputFormat::Jsonl => {
+            for record in records {
+                println!("{}", to_json_line(record)?);
+            }
+        }
+        OutputFormat::Tsv => {
+            println!("{}", R::TSV_HEADER.join("\t"));
+            for record in records {
+                println!("{}", tsv_row(record.tsv_fields()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints a single record, e.g. the config paths. `json` prints an object
+/// rather than an array.
+pub fn print_record<R: Record>(format: OutputFormat, record: &R) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", to_json_pretty(record)?),
+        _ => print_records(format, std::slice::from_ref(record))?,
+    }
+
+    Ok(())
+}
+
+fn to_json_pretty<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    serde_json::to_string_pretty(value).context("Could not serialize output to json")
+}
+
+fn to_json_line<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    serde_json::to_string(value).context("Could not serialize output to json")
+}
+
+fn tsv_row(fields: Vec<String>) -> String {
+    fields
+        .iter()
+        .map(|field| escape_tsv(field))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Escapes characters that would break a TSV row, so every record stays on
+/// one line with a fixed number of columns.
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[derive(Serialize)]
+pub struct CommandRecord {
+    pub id: u32,
+    pub command: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub collection: String,
+    pub is_template: bool,
+    pub parameters: Vec<String>,
+    pub shell: Option<String>,
+    pub created_at: Option<Timestamp>,
+    pub updated_at: Option<Timestamp>,
+    pub last_run_at: Option<Timestamp>,
+    pub run_count: u32,
+    pub alias: Option<String>,
+}
+
+impl From<&SavedCommand> for CommandRecord {
+    fn from(cmd: &SavedCommand) -> Self {
+        CommandRecord {
+            id: cmd.id,
+            command: cmd.command.clone(),
+            description: cmd.description_if_set().map(str::to_string),
+            tags: cmd.tags.clone().unwrap_or_default(),
+            collection: cmd.collection.clone(),
+            is_template: cmd.is_template,
+            parameters: extract_parameters(&cmd.command),
+            shell: cmd.shell.clone(),
+            created_at: cmd.created_at,
+            updated_at: cmd.updated_at,
+            last_run_at: cmd.last_run_at,
+            run_count: cmd.run_count,
+            alias: cmd.alias.clone(),
+        }
+    }
+}
+
+impl Record for CommandRecord {
+    const TSV_HEADER: &'static [&'static str] = &[
+        "id",
+        "command",
+        "description",
+        "tags",
+        "collection",
+        "is_template",
+        "parameters",
+        "shell",
+        "created_at",
+        "updated_at",
+        "last_run_at",
+        "run_count",
+        "alias",
+    ];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.command.clone(),
+            self.description.clone().unwrap_or_default(),
+            self.tags.join(","),
+            self.collection.clone(),
+            self.is_template.to_string(),
+            self.parameters.join(","),
+            self.shell.clone().unwrap_or_default(),
+            timestamp_field(self.created_at),
+            timestamp_field(self.updated_at),
+            timestamp_field(self.last_run_at),
+            self.run_count.to_string(),
+            self.alias.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+/// A single command in full, as printed by `show`.
+#[derive(Serialize)]
+pub struct CommandDetailRecord {
+    #[serde(flatten)]
+    pub command: CommandRecord,
+    pub parameter_definitions: Vec<ParameterRecord>,
+    pub output: Option<CapturedOutput>,
+}
+
+impl From<&SavedCommand> for CommandDetailRecord {
+    fn from(cmd: &SavedCommand) -> Self {
+        CommandDetailRecord {
+            command: CommandRecord::from(cmd),
+            parameter_definitions: parse_parameters(&cmd.command)
+                .iter()
+                .map(ParameterRecord::from)
+                .collect(),
+            output: cmd.output.clone(),
+        }
+    }
+}
+
+impl Record for CommandDetailRecord {
+    const TSV_HEADER: &'static [&'static str] = &[
+        "id",
+        "command",
+        "description",
+        "tags",
+        "collection",
+        "is_template",
+        "parameters",
+        "shell",
+        "created_at",
+        "updated_at",
+        "last_run_at",
+        "run_count",
+        "alias",
+        "output",
+    ];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        let mut fields = self.command.tsv_fields();
+        fields.push(
+            self.output
+                .as_ref()
+                .map(|output| output.lines.join("\n"))
+                .unwrap_or_default(),
+        );
+        fields
+    }
+}
+
+/// Output stored by `run --capture`, as printed by `show --output`.
+impl Record for CapturedOutput {
+    const TSV_HEADER: &'static [&'static str] = &["captured_at", "exit_code", "lines"];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![
+            self.captured_at.to_string(),
+            self.exit_code.to_string(),
+            self.lines.join("\n"),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct ParameterRecord {
+    pub name: String,
+    pub help: Option<String>,
+    pub default: Option<String>,
+    pub choices: Option<Vec<String>>,
+    pub choices_command: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub required: bool,
+    pub pattern: Option<String>,
+}
+
+impl From<&TemplateParam> for ParameterRecord {
+    fn from(param: &TemplateParam) -> Self {
+        let (choices, choices_command) = match &param.choices {
+            Some(Choices::List(list)) => (Some(list.clone()), None),
+            Some(Choices::Command(command)) => (None, Some(command.clone())),
+            None => (None, None),
+        };
+
+        ParameterRecord {
+            name: param.name.clone(),
+            help: param.help.clone(),
+            default: param.default.clone(),
+            choices,
+            choices_command,
+            kind: match param.kind {
+                ParamKind::Text => "text",
+                ParamKind::Int => "int",
+                ParamKind::Path => "path",
+            },
+            required: param.required,
+            pattern: param.pattern.as_ref().map(|p| p.as_str().to_string()),
+        }
+    }
+}
+
+fn timestamp_field(timestamp: Option<Timestamp>) -> String {
+    timestamp.map(|t| t.to_string()).unwrap_or_default()
+}
+
+#[derive(Serialize)]
+pub struct TagRecord {
+    pub tag: String,
+    pub count: usize,
+}
+
+impl Record for TagRecord {
+    const TSV_HEADER: &'static [&'static str] = &["tag", "count"];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![self.tag.clone(), self.count.to_string()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct CollectionRecord {
+    pub collection: String,
+    pub count: usize,
+}
+
+impl Record for CollectionRecord {
+    const TSV_HEADER: &'static [&'static str] = &["collection", "count"];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![self.collection.clone(), self.count.to_string()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct VariableRecord {
+    pub name: String,
+    pub value: String,
+    pub source: String,
+}
+
+impl Record for VariableRecord {
+    const TSV_HEADER: &'static [&'static str] = &["name", "value", "source"];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![self.name.clone(), self.value.clone(), self.source.clone()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct HistoryRecord {
+    pub entry: usize,
+    pub started_at: Timestamp,
+    pub id: u32,
+    pub command: String,
+    pub cwd: String,
+    pub shell: String,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+}
+
+impl HistoryRecord {
+    pub fn new(entry: usize, run: &HistoryEntry) -> Self {
+        HistoryRecord {
+            entry,
+            started_at: run.started_at,
+            id: run.id,
+            command: run.command.clone(),
+            cwd: run.cwd.clone(),
+            shell: run.shell.clone(),
+            exit_code: run.exit_code,
+            duration_ms: run.duration_ms,
+        }
+    }
+}
+
+impl Record for HistoryRecord {
+    const TSV_HEADER: &'static [&'static str] = &[
+        "entry",
+        "started_at",
+        "id",
+        "command",
+        "cwd",
+        "shell",
+        "exit_code",
+        "duration_ms",
+    ];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![
+            self.entry.to_string(),
+            self.started_at.to_string(),
+            self.id.to_string(),
+            self.command.clone(),
+            self.cwd.clone(),
+            self.shell.clone(),
+            self.exit_code.to_string(),
+            self.duration_ms.to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConfigRecord {
+    pub config_dir: String,
+    pub config_path: String,
+    pub storage_path: String,
+}
+
+impl Record for ConfigRecord {
+    const TSV_HEADER: &'static [&'static str] = &["config_dir", "config_path", "storage_path"];
+
+    fn tsv_fields(&self) -> Vec<String> {
+        vec![
+            self.config_dir.clone(),
+            self.config_path.clone(),
+            self.storage_path.clone(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tsv_fields_stay_on_one_line() {
+        let row = tsv_row(vec![
+            "printf 'a\tb'".to_string(),
+            "line one\nline two".to_string(),
+            "C:\\path".to_string(),
+        ]);
+        assert_eq!(row, "printf 'a\\tb'\tline one\\nline two\tC:\\\\path");
+        assert_eq!(row.split('\t').count(), 3);
+    }
+
+    #[test]
+    fn test_command_detail_record_fields() {
+        let text = "id = 7\ncommand = 'deploy {{env|Target:dev|prod}} {{n:int}}'\n\
+                    description = 'Deploy'\n\
+                    [output]\ncaptured_at = '2024-02-29T12:34:56Z'\nexit_code = 1\n\
+                    lines = ['ok', 'failed']";
+        let cmd: SavedCommand = toml::from_str(text).unwrap();
+        let record = CommandDetailRecord::from(&cmd);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json_line(&record).unwrap()).unwrap();
+        assert_eq!(json["id"], 7);
+        assert_eq!(json["parameters"], serde_json::json!(["env", "n"]));
+        assert_eq!(json["output"]["exit_code"], 1);
+        assert_eq!(
+            json["parameter_definitions"][0],
+            serde_json::json!({
+                "name": "env",
+                "help": "Target",
+                "default": null,
+                "choices": ["dev", "prod"],
+                "choices_command": null,
+                "type": "text",
+                "required": false,
+                "pattern": null,
+            })
+        );
+        assert_eq!(json["parameter_definitions"][1]["type"], "int");
+
+        let fields = record.tsv_fields();
+        assert_eq!(fields.len(), CommandDetailRecord::TSV_HEADER.len());
+        assert_eq!(fields.last().unwrap(), "ok\nfailed");
+        assert_eq!(
+            tsv_row(fields).split('\t').count(),
+            CommandDetailRecord::TSV_HEADER.len()
+        );
+
+        let output = cmd.output.unwrap();
+        assert_eq!(
+            output.tsv_fields(),
+            vec!["2024-02-29T12:34:56Z", "1", "ok\nfailed"]
+        );
+    }
+}
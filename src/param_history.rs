@@ -0,0 +1,151 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    config::get_param_history_path,
+    storage::{read_toml, update_toml},
+};
+
+/// Number of values remembered for each parameter.
+const MAX_VALUES: usize = 10;
+
+/// Recently used values of a command's parameters, most recent first.
+pub type RecentValues = BTreeMap<String, Vec<String>>;
+
+/// Every value of a command's last run, empty ones included.
+pub type LastValues = BTreeMap<String, String>;
+
+/// The values a command was run with before.
+#[derive(Debug, Default)]
+pub struct PreviousValues {
+    /// Offered first when prompting
+    pub recent: RecentValues,
+    /// Reused as they are by `run --last`
+    pub last: Option<LastValues>,
+}
+
+/// Template values used in earlier runs, keyed by command id.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ParamHistory {
+    #[serde(default)]
+    commands: BTreeMap<String, RecentValues>,
+    #[serde(default)]
+    last: BTreeMap<String, LastValues>,
+}
+
+impl ParamHistory {
+    pub fn load() -> Result<Self> {
+        Ok(read_toml(&get_param_history_path())?.unwrap_or_default())
+    }
+
+    /// Earlier values for command `id`, empty if it was never run.
+    pub fn previous(&self, id: u32) -> PreviousValues {
+        let key = id.to_string();
+        PreviousValues {
+            recent: self.commands.get(&key).cloned().unwrap_or_default(),
+            last: self.last.get(&key).cloned(),
+        }
+    }
+
+    fn record(&mut self, id: u32, values: &HashMap<String, String>) {
+        self.last.insert(
+            id.to_string(),
+            values
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        );
+        let recent = self.commands.entry(id.to_string()).or_default();
+
+        for (name, value) in values {
+            if value.is_empty() {
+                continue;
+            }
+            let previous = recent.entry(name.clone()).or_default();
+            previous.retain(|v| v != value);
+            previous.insert(0, value.clone());
+            previous.truncate(MAX_VALUES);
+        }
+    }
+}
+
+/// Remembers the values command `id` was just run with.
+pub fn record_values(id: u32, values: &HashMap<String, String>) -> Result<()> {
+    update_toml(&get_param_history_path(), |history: &mut ParamHistory| {
+        history.record(id, values);
+        Ok(())
+    })
+}
+
+/// Drops the remembered values of deleted commands, so a new command that
+/// reuses an id starts out clean.
+pub fn forget_commands(ids: &[u32]) -> Result<()> {
+    let path = get_param_history_path();
+    if !path.exists() {
+        return Ok(());
+    }
+
+    update_toml(&path, |history: &mut ParamHistory| {
+        for id in ids {
+            history.commands.remove(&id.to_string());
+            history.last.remove(&id.to_string());
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_keeps_recent_values_first() {
+        let mut history = ParamHistory::default();
+        for host in ["db1", "db2", "db1"] {
+            history.record(3, &HashMap::from([("host".to_string(), host.to_string())]));
+        }
+        for n in 0..MAX_VALUES + 5 {
+            history.record(3, &HashMap::from([("n".to_string(), n.to_string())]));
+        }
+        history.record(3, &HashMap::from([("empty".to_string(), String::new())]));
+
+        let previous = history.previous(3);
+        let recent = previous.recent;
+        assert_eq!(recent["host"], vec!["db1", "db2"]);
+        assert_eq!(recent["n"].len(), MAX_VALUES);
+        assert_eq!(recent["n"][0], (MAX_VALUES + 4).to_string());
+        assert!(!recent.contains_key("empty"));
+        assert!(history.previous(4).recent.is_empty());
+    }
+
+    #[test]
+    fn test_record_keeps_last_run_whole() {
+        let mut history = ParamHistory::default();
+        history.record(
+            3,
+            &HashMap::from([
+                ("host".to_string(), "db1".to_string()),
+                ("flags".to_string(), "-v".to_string()),
+            ]),
+        );
+        history.record(
+            3,
+            &HashMap::from([
+                ("host".to_string(), "db2".to_string()),
+                ("flags".to_string(), String::new()),
+            ]),
+        );
+
+        let previous = history.previous(3);
+        assert_eq!(
+            previous.last,
+            Some(LastValues::from([
+                ("flags".to_string(), String::new()),
+                ("host".to_string(), "db2".to_string()),
+            ]))
+        );
+        assert_eq!(previous.recent["flags"], vec!["-v"]);
+        assert!(history.previous(4).last.is_none());
+    }
+}
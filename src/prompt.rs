@@ -0,0 +1,208 @@
+//! A small line editor for parameter prompts, with history and path
+//! completion.
+
+use anyhow::Result;
+use crossterm::{
+    cursor::MoveToColumn,
+    event::{read, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal::{self, Clear, ClearType},
+    QueueableCommand,
+};
+use std::{fs, io::Write, path::PathBuf};
+
+use crate::fuzzy::{terminal_output, FuzzyPicker};
+
+/// Reads a line from the terminal. Up and Down step through `history`
+/// (most recent first), Ctrl-R picks from it with the fuzzy picker, and Tab
+/// completes paths when `complete_paths` is set. Returns `None` if the
+/// prompt is cancelled with Esc or Ctrl-C.
+pub fn read_line(prompt: &str, complete_paths: bool, history: &[String]) -> Result<Option<String>> {
+    terminal::enable_raw_mode()?;
+    let result = edit_line(prompt, complete_paths, history);
+    let _ = terminal::disable_raw_mode();
+    let _ = writeln!(terminal_output());
+    result
+}
+
+fn edit_line(prompt: &str, complete_paths: bool, history: &[String]) -> Result<Option<String>> {
+    let mut stdout = terminal_output();
+    let mut input = String::new();
+    // What was typed before stepping into the history with Up
+    let mut typed = String::new();
+    let mut position: Option<usize> = None;
+    redraw(&mut stdout, prompt, &input)?;
+
+    loop {
+        let Event::Key(key) = read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Enter => return Ok(Some(input)),
+            KeyCode::Esc => return Ok(None),
+            KeyCode::Char('c') if ctrl => return Ok(None),
+            KeyCode::Char('u') if ctrl => input.clear(),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Up if !history.is_empty() => {
+                let next = position.map_or(0, |i| (i + 1).min(history.len() - 1));
+                if position.is_none() {
+                    typed = input.clone();
+                }
+                position = Some(next);
+                input = history[next].clone();
+            }
+            KeyCode::Down => match position {
+                Some(0) => {
+                    position = None;
+                    input = std::mem::take(&mut typed);
+                }
+                Some(i) => {
+                    position = Some(i - 1);
+                    input = history[i - 1].clone();
+                }
+                None => {}
+            },
+            KeyCode::Char('r') if ctrl && !history.is_empty() => {
+                // The picker leaves raw mode when it is done
+                let picked = FuzzyPicker::new(history).pick();
+                terminal::enable_raw_mode()?;
+                if let Ok(Some(value)) = picked {
+                    input = value;
+                    position = None;
+                }
+            }
+            KeyCode::Tab if complete_paths => {
+                let (completed, candidates) = complete_path(&input);
+                input = completed;
+                if !candidates.is_empty() {
+                    // Raw mode needs explicit carriage returns
+                    write!(stdout, "\r\n{}\r\n", candidates.join("  "))?;
+                }
+            }
+            KeyCode::Char(c) if !ctrl => input.push(c),
+            _ => {}
+        }
+
+        redraw(&mut stdout, prompt, &input)?;
+    }
+}
+
+fn redraw(stdout: &mut dyn Write, prompt: &str, input: &str) -> Result<()> {
+    stdout.queue(MoveToColumn(0))?;
+    stdout.queue(Clear(ClearType::CurrentLine))?;
+    write!(stdout, "{}{}", prompt, input)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Completes the last component of a path. Returns the completed input and,
+/// when more than one entry matches, the matching names. Hidden entries are
+/// only offered once the component starts with a dot.
+pub fn complete_path(input: &str) -> (String, Vec<String>) {
+    if input == "~" {
+        return ("~/".to_string(), vec![]);
+    }
+
+    let (dir_part, prefix) = match input.rfind('/') {
+        Some(i) => input.split_at(i + 1),
+        None => ("", input),
+    };
+    let dir = if dir_part.is_empty() {
+        PathBuf::from(".")
+    } else {
+        expand_tilde(dir_part)
+    };
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (input.to_string(), vec![]);
+    };
+
+    let mut matches: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            Some(if entry.path().is_dir() {
+                name + "/"
+            } else {
+                name
+            })
+        })
+        .collect();
+    matches.sort();
+
+    match matches.len() {
+        0 => (input.to_string(), vec![]),
+        1 => (format!("{}{}", dir_part, matches[0]), vec![]),
+        _ => (format!("{}{}", dir_part, common_prefix(&matches)), matches),
+    }
+}
+
+fn common_prefix(names: &[String]) -> String {
+    let mut prefix = names[0].clone();
+    for name in &names[1..] {
+        let len = prefix
+            .char_indices()
+            .zip(name.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or_else(|| prefix.len().min(name.len()));
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+/// Expands a leading `~` to the home directory.
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_complete_path() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("backups")).unwrap();
+        fs::write(dir.path().join("backup.sh"), "").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        let base = format!("{}/", dir.path().display());
+
+        assert_eq!(
+            complete_path(&format!("{}n", base)),
+            (format!("{}notes.txt", base), vec![])
+        );
+        assert_eq!(
+            complete_path(&format!("{}b", base)),
+            (
+                format!("{}backup", base),
+                vec!["backup.sh".to_string(), "backups/".to_string()]
+            )
+        );
+        assert_eq!(
+            complete_path(&format!("{}backups", base)),
+            (format!("{}backups/", base), vec![])
+        );
+        assert_eq!(complete_path(&format!("{}x", base)).0, format!("{}x", base));
+        assert_eq!(
+            complete_path(&format!("{}.", base)),
+            (format!("{}.hidden", base), vec![])
+        );
+    }
+}
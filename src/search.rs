@@ -0,0 +1,365 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    cmd::{SavedCommand, TagFilter},
+    timestamp::Timestamp,
+};
+
+/// How free text and `desc:`/`cmd:` qualifiers are matched.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MatchMode {
+    /// Fuzzy matching, the same as the picker.
+    Fuzzy,
+    /// Case-insensitive substring matching.
+    Exact,
+    /// Regular expressions (use `(?-i)` for case-sensitive matching).
+    Regex,
+}
+
+/// What `--first` prints for the best match.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FirstField {
+    Id,
+    Command,
+}
+
+/// Order of `shelf list`.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum SortOrder {
+    /// Oldest first, the order commands were stacked in
+    #[default]
+    Id,
+    /// Most frequently and recently used first
+    Frecency,
+    /// Most recently used first
+    Recent,
+    /// Alphabetically by command
+    Alpha,
+}
+
+#[derive(Debug)]
+enum IdBound {
+    Eq(u32),
+    Gt(u32),
+    Ge(u32),
+    Lt(u32),
+    Le(u32),
+    Range(u32, u32),
+}
+
+impl IdBound {
+    fn parse(value: &str) -> Result<Self> {
+        let number = |s: &str| {
+            s.trim()
+                .parse::<u32>()
+                .with_context(|| format!("Invalid id in query: '{}'", value))
+        };
+
+        Ok(if let Some((from, to)) = value.split_once("..") {
+            IdBound::Range(number(from)?, number(to)?)
+        } else if let Some(rest) = value.strip_prefix(">=") {
+            IdBound::Ge(number(rest)?)
+        } else if let Some(rest) = value.strip_prefix("<=") {
+            IdBound::Le(number(rest)?)
+        } else if let Some(rest) = value.strip_prefix('>') {
+            IdBound::Gt(number(rest)?)
+        } else if let Some(rest) = value.strip_prefix('<') {
+            IdBound::Lt(number(rest)?)
+        } else {
+            IdBound::Eq(number(value)?)
+        })
+    }
+
+    fn matches(&self, id: u32) -> bool {
+        match *self {
+            IdBound::Eq(n) => id == n,
+            IdBound::Gt(n) => id > n,
+            IdBound::Ge(n) => id >= n,
+            IdBound::Lt(n) => id < n,
+            IdBound::Le(n) => id <= n,
+            IdBound::Range(from, to) => (from..=to).contains(&id),
+        }
+    }
+}
+
+/// A single text condition, compiled for the chosen `MatchMode`.
+struct Pattern {
+    text: String,
+    regex: Option<Regex>,
+}
+
+impl Pattern {
+    fn new(text: &str, mode: MatchMode) -> Result<Self> {
+        let regex = match mode {
+            MatchMode::Regex => Some(
+                RegexBuilder::new(text)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Invalid regex in query: '{}'", text))?,
+            ),
+            _ => None,
+        };
+
+        Ok(Pattern {
+            text: text.to_lowercase(),
+            regex,
+        })
+    }
+
+    /// Score of `haystack` against this pattern, or `None` if it does not match.
+    fn score(&self, matcher: &SkimMatcherV2, haystack: &str, mode: MatchMode) -> Option<i64> {
+        match mode {
+            MatchMode::Fuzzy => matcher.fuzzy_match(&haystack.to_lowercase(), &self.text),
+            MatchMode::Exact => {
+                let haystack = haystack.to_lowercase();
+                haystack
+                    .contains(&self.text)
+                    .then(|| matcher.fuzzy_match(&haystack, &self.text).unwrap_or(0))
+            }
+            // Built case-insensitive, so the text is matched as it is and
+            // `(?-i)` can turn that off
+            MatchMode::Regex => self.regex.as_ref()?.is_match(haystack).then_some(0),
+        }
+    }
+}
+
+/// A parsed `shelf search` query. Words are matched against the whole
+/// command; `tag:`, `desc:`, `cmd:`, `col:` and `id:` qualifiers narrow the
+/// match to a single field.
+pub struct Query {
+    mode: MatchMode,
+    text: Option<Pattern>,
+    desc: Vec<Pattern>,
+    cmd: Vec<Pattern>,
+    collections: Vec<String>,
+    ids: Vec<IdBound>,
+    tags: TagFilter,
+    matcher: SkimMatcherV2,
+}
+
+impl Query {
+    pub fn parse(query: &str, mode: MatchMode) -> Result<Self> {
+        let mut words = Vec::new();
+        let mut desc = Vec::new();
+        let mut cmd = Vec::new();
+        let mut collections = Vec::new();
+        let mut ids = Vec::new();
+        let mut tags = Vec::new();
+
+        for token in query.split_whitespace() {
+            match token.split_once(':') {
+                Some(("tag", value)) if !value.is_empty() => tags.push(value.to_string()),
+                Some(("desc", value)) if !value.is_empty() => desc.push(Pattern::new(value, mode)?),
+                Some(("cmd", value)) if !value.is_empty() => cmd.push(Pattern::new(value, mode)?),
+                Some(("col", value)) if !value.is_empty() => collections.push(value.to_string()),
+                Some(("id", value)) if !value.is_empty() => ids.push(IdBound::parse(value)?),
+                _ => words.push(token),
+            }
+        }
+
+        let text = if words.is_empty() {
+            None
+        } else {
+            Some(Pattern::new(&words.join(" "), mode)?)
+        };
+
+        Ok(Query {
+            mode,
+            text,
+            desc,
+            cmd,
+            collections,
+            ids,
+            tags: TagFilter::new(&tags, false),
+            matcher: SkimMatcherV2::default(),
+        })
+    }
+
+    /// Ranking score of `cmd`, or `None` if it does not match the query.
+    /// Higher is better.
+    pub fn score(&self, cmd: &SavedCommand) -> Option<i64> {
+        if !self.ids.iter().all(|bound| bound.matches(cmd.id)) {
+            return None;
+        }
+        if !self.collections.is_empty() && !self.collections.contains(&cmd.collection) {
+            return None;
+        }
+        if !self.tags.matches(cmd) {
+            return None;
+        }
+
+        let mut score = 0;
+
+        for pattern in &self.cmd {
+            score += pattern.score(&self.matcher, &cmd.command, self.mode)?;
+        }
+        for pattern in &self.desc {
+            score += pattern.score(&self.matcher, &cmd.description, self.mode)?;
+        }
+        if let Some(pattern) = &self.text {
+            score += pattern.score(&self.matcher, &searchable_text(cmd), self.mode)?;
+        }
+
+        Some(score)
+    }
+}
+
+/// Text free words are matched against: the command, its description and tags.
+fn searchable_text(cmd: &SavedCommand) -> String {
+    let mut text = format!("{} {}", cmd.command, cmd.description);
+    if let Some(tags) = &cmd.tags {
+        text.push(' ');
+        text.push_str(&tags.join(" "));
+    }
+    text
+}
+
+/// Commands matching `query`, best match first. Ties keep shelf order.
+pub fn rank<'a>(commands: &'a [SavedCommand], query: &Query) -> Vec<&'a SavedCommand> {
+    let mut ranked: Vec<(i64, &SavedCommand)> = commands
+        .iter()
+        .filter_map(|cmd| query.score(cmd).map(|score| (score, cmd)))
+        .collect();
+
+    ranked.sort_by_key(|(score, _)| -score);
+    ranked.into_iter().map(|(_, cmd)| cmd).collect()
+}
+
+/// Frequency and recency of use in one number: the run count, weighted by
+/// how long ago the command was last run or copied. Unused commands score 0.
+pub fn frecency(cmd: &SavedCommand, now: Timestamp) -> u64 {
+    let Some(last_run) = cmd.last_run_at else {
+        return 0;
+    };
+
+    let weight = match last_run.days_before(now) {
+        0..=3 => 100,
+        4..=14 => 70,
+        15..=31 => 50,
+        32..=90 => 30,
+        _ => 10,
+    };
+    cmd.run_count as u64 * weight
+}
+
+/// Picker boost for a frecency score. Logarithmic, so heavy use lifts a
+/// command over similar matches without burying better ones.
+pub fn frecency_boost(frecency: u64) -> i64 {
+    ((frecency as f64).ln_1p() * 10.0) as i64
+}
+
+/// Sorts commands for `shelf list`. Ties keep shelf order.
+pub fn sort_commands(commands: &mut [SavedCommand], order: SortOrder) {
+    let now = Timestamp::now();
+
+    match order {
+        SortOrder::Id => commands.sort_by_key(|cmd| cmd.id),
+        SortOrder::Frecency => commands.sort_by_key(|cmd| std::cmp::Reverse(frecency(cmd, now))),
+        SortOrder::Recent => commands.sort_by_key(|cmd| std::cmp::Reverse(cmd.last_run_at)),
+        SortOrder::Alpha => commands.sort_by_key(|cmd| cmd.command.to_lowercase()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: u32, command: &str, description: &str, tags: &[&str]) -> SavedCommand {
+        let text = format!(
+            "id = {}\ncommand = '{}'\ndescription = '{}'",
+            id, command, description
+        );
+        SavedCommand {
+            tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+            ..toml::from_str(&text).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_search_query() {
+        let commands = [
+            command(1, "rsync -av src/ backup/", "Backup sources", &["backup"]),
+            command(2, "docker ps -a", "All containers", &["docker"]),
+            command(
+                3,
+                "docker image prune",
+                "Prune images",
+                &["docker", "cleanup"],
+            ),
+        ];
+        let ids = |query: &str, mode: MatchMode| {
+            rank(&commands, &Query::parse(query, mode).unwrap())
+                .iter()
+                .map(|cmd| cmd.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("rsyn", MatchMode::Fuzzy), vec![1]);
+        assert_eq!(ids("tag:docker", MatchMode::Fuzzy), vec![2, 3]);
+        assert_eq!(ids("tag:docker tag:!cleanup", MatchMode::Fuzzy), vec![2]);
+        assert_eq!(ids("desc:backup", MatchMode::Fuzzy), vec![1]);
+        assert_eq!(ids("cmd:prune", MatchMode::Exact), vec![3]);
+        assert_eq!(ids("id:>1", MatchMode::Fuzzy), vec![2, 3]);
+        assert_eq!(ids("id:1..2 docker", MatchMode::Fuzzy), vec![2]);
+        assert!(ids("rsyn backup", MatchMode::Exact).is_empty());
+        assert_eq!(ids("^docker (ps|image)", MatchMode::Regex), vec![2, 3]);
+        assert!(Query::parse("id:abc", MatchMode::Fuzzy).is_err());
+        assert!(Query::parse("(", MatchMode::Regex).is_err());
+    }
+
+    #[test]
+    fn test_regex_case_sensitivity() {
+        let commands = [
+            command(1, "make Release", "", &[]),
+            command(2, "make release", "", &[]),
+        ];
+        let ids = |query: &str| {
+            rank(&commands, &Query::parse(query, MatchMode::Regex).unwrap())
+                .iter()
+                .map(|cmd| cmd.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("RELEASE"), vec![1, 2]);
+        assert_eq!(ids("(?-i)Release"), vec![1]);
+        assert_eq!(ids("cmd:(?-i)release"), vec![2]);
+    }
+
+    #[test]
+    fn test_sort_commands() {
+        let mut commands: Vec<SavedCommand> = ["ls", "git status", "docker ps", "cargo build"]
+            .into_iter()
+            .zip(1..)
+            .map(|(text, id)| command(id, text, "", &[]))
+            .collect();
+        let now = Timestamp::now();
+        let long_ago: Timestamp = "2020-01-01T00:00:00Z".parse().unwrap();
+
+        // Used often, but not for years
+        commands[0].run_count = 50;
+        commands[0].last_run_at = Some(long_ago);
+        // Used a few times today
+        commands[1].run_count = 10;
+        commands[1].last_run_at = Some(now);
+        // Used once today
+        commands[2].run_count = 1;
+        commands[2].last_run_at = Some(now);
+
+        let ids = |commands: &[SavedCommand]| commands.iter().map(|c| c.id).collect::<Vec<_>>();
+
+        sort_commands(&mut commands, SortOrder::Frecency);
+        assert_eq!(ids(&commands), vec![2, 1, 3, 4]);
+        sort_commands(&mut commands, SortOrder::Recent);
+        assert_eq!(ids(&commands), vec![2, 3, 1, 4]);
+        sort_commands(&mut commands, SortOrder::Alpha);
+        assert_eq!(ids(&commands), vec![4, 3, 2, 1]);
+        sort_commands(&mut commands, SortOrder::Id);
+        assert_eq!(ids(&commands), vec![1, 2, 3, 4]);
+
+        assert!(frecency_boost(frecency(&commands[1], now)) > 0);
+        assert_eq!(frecency_boost(frecency(&commands[3], now)), 0);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+/// Picks the shell used to run a saved command. The first override that is
+/// set wins, then `$SHELL`, then the platform default.
+pub fn resolve_shell(overrides: &[Option<&str>]) -> String {
+    if let Some(shell) = overrides.iter().flatten().find(|s| !s.trim().is_empty()) {
+        return shell.trim().to_string();
+    }
+
+    match std::env::var("SHELL") {
+        Ok(shell) if !shell.trim().is_empty() => shell,
+        _ => default_shell().to_string(),
+    }
+}
+
+fn default_shell() -> &'static str {
+    if cfg!(windows) {
+        "cmd"
+    } else {
+        "sh"
+    }
+}
+
+/// Name of the shell binary without its directory or `.exe` suffix,
+/// e.g. `/usr/bin/zsh` -> `zsh`.
+pub fn shell_name(shell: &str) -> String {
+    Path::new(shell)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Builds a `Command` that hands `script` to `shell` as a single string,
+/// so pipes, redirects, quoting and globbing behave as if typed.
+pub fn shell_command(shell: &str, script: &str) -> Command {
+    let mut command = Command::new(shell);
+
+    match shell_name(shell).as_str() {
+        "cmd" => command.arg("/C").arg(script),
+        _ => command.arg("-c").arg(script),
+    };
+
+    command
+}
+
+/// Whether a point in a command line is inside quotes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuoteState {
+    Unquoted,
+    Single,
+    Double,
+}
+
+/// The quoting in effect at the end of `text`, read with `shell`'s rules,
+/// when it starts out in `state`.
+pub fn quote_state_after(shell: &str, text: &str, mut state: QuoteState) -> QuoteState {
+    let name = shell_name(shell);
+    // Escapes the next character outside single quotes
+    let escape = match name.as_str() {
+        "cmd" => None,
+        "powershell" | "pwsh" => Some('`'),
+        _ => Some('\\'),
+    };
+
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        state = match state {
+            QuoteState::Unquoted if c == '"' => QuoteState::Double,
+            QuoteState::Unquoted if c == '\'' && name != "cmd" => QuoteState::Single,
+            QuoteState::Single if c == '\'' => QuoteState::Unquoted,
+            QuoteState::Double if c == '"' => QuoteState::Unquoted,
+            // fish allows \\ and \' inside single quotes
+            QuoteState::Single if c == '\\' && name == "fish" => {
+                chars.next();
+                state
+            }
+            QuoteState::Single => state,
+            _ if Some(c) == escape => {
+                chars.next();
+                state
+            }
+            _ => state,
+        };
+    }
+
+    state
+}
+
+/// Quotes `value` for where it is spliced in: as a word of its own when
+/// `state` is unquoted, or escaped so it stays literal inside the quotes
+/// the command already opened there.
+pub fn quote_in(shell: &str, value: &str, state: QuoteState) -> String {
+    match (state, shell_name(shell).as_str()) {
+        (QuoteState::Unquoted, _) => quote(shell, value),
+        (QuoteState::Single, "fish") => value.replace('\\', "\\\\").replace('\'', "\\'"),
+        (QuoteState::Single, "powershell" | "pwsh") => value.replace('\'', "''"),
+        (QuoteState::Single, _) => value.replace('\'', "'\\''"),
+        (QuoteState::Double, "cmd") => escape_cmd(value),
+        (QuoteState::Double, "powershell" | "pwsh") => escape_chars(value, "`\"$", '`'),
+        (QuoteState::Double, "fish") => escape_chars(value, "\\\"$", '\\'),
+        (QuoteState::Double, _) => escape_chars(value, "\\\"$`", '\\'),
+    }
+}
+
+fn escape_chars(value: &str, special: &str, escape: char) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push(escape);
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes a value inside cmd's double quotes. cmd has no escape there, so
+/// quotes are doubled, and `%` is written as `"^%"`, which leaves the quotes
+/// for a moment so `%VAR%` cannot expand.
+fn escape_cmd(value: &str) -> String {
+    value.replace('"', "\"\"").replace('%', "\"^%\"")
+}
+
+/// Quotes `value` so `shell` reads it back as a single word, unchanged.
+/// Values made only of characters no shell treats specially are left bare.
+pub fn quote(shell: &str, value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@+=:,./-".contains(c);
+    // zsh expands a leading `=cmd` to the path of cmd
+    if !value.is_empty() && !value.starts_with('=') && value.chars().all(is_safe) {
+        return value.to_string();
+    }
+
+    match shell_name(shell).as_str() {
+        "cmd" => format!("\"{}\"", escape_cmd(value)),
+        "powershell" | "pwsh" => format!("'{}'", value.replace('\'', "''")),
+        // fish allows escaping backslashes and quotes inside single quotes
+        "fish" => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+        _ => format!("'{}'", value.replace('\'', "'\\''")),
+    }
+}
+
+/// Exit code to report for a finished child. On Unix, a child killed by a
+/// signal reports `128 + signal`, like shells do.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            quote("sh", "db-1.example.com:5432"),
+            "db-1.example.com:5432"
+        );
+        assert_eq!(quote("sh", ""), "''");
+        assert_eq!(quote("/bin/bash", "it's"), "'it'\\''s'");
+        assert_eq!(quote("fish", "it's a \\"), "'it\\'s a \\\\'");
+        assert_eq!(quote("pwsh", "it's"), "'it''s'");
+        assert_eq!(quote("cmd", "say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote("cmd", "100%"), "\"100\"^%\"\"");
+        assert_eq!(quote("zsh", "=ls"), "'=ls'");
+        assert_eq!(quote("zsh", "a=b"), "a=b");
+    }
+
+    #[test]
+    fn test_quote_state_after() {
+        let state = |shell: &str, text: &str| quote_state_after(shell, text, QuoteState::Unquoted);
+        assert_eq!(state("sh", "echo 'a"), QuoteState::Single);
+        assert_eq!(state("sh", "echo \"it's"), QuoteState::Double);
+        assert_eq!(state("sh", "echo \\\"a"), QuoteState::Unquoted);
+        assert_eq!(state("sh", "echo \"a\\\"b"), QuoteState::Double);
+        assert_eq!(state("sh", "echo 'a\\'"), QuoteState::Unquoted);
+        assert_eq!(state("fish", "echo 'a\\'"), QuoteState::Single);
+        assert_eq!(state("cmd", "echo 'a"), QuoteState::Unquoted);
+        assert_eq!(state("pwsh", "echo \"a`\""), QuoteState::Double);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_quoted_values_survive_the_shell() {
+        let values = [
+            "two words",
+            "it's \"quoted\"",
+            "$HOME ${PATH}",
+            "`id` $(id)",
+            "a; exit 7 && echo | cat > out",
+            "line one\nline two\n",
+            "back\\slash * ? [a] ~ # !",
+            "",
+        ];
+
+        for value in values {
+            let scripts = [
+                format!("printf %s {}", quote("sh", value)),
+                format!(
+                    "printf %s \"{}\"",
+                    quote_in("sh", value, QuoteState::Double)
+                ),
+                format!("printf %s '{}'", quote_in("sh", value, QuoteState::Single)),
+            ];
+            for script in scripts {
+                let output = shell_command("sh", &script).output().unwrap();
+                assert_eq!(String::from_utf8_lossy(&output.stdout), value, "{}", script);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Reading commands back from the history files of bash, zsh and fish.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::{fs, path::PathBuf};
+
+use crate::shell::shell_name;
+
+/// A shell whose history file can be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HistoryShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl HistoryShell {
+    /// The shell in `$SHELL`, or bash when it is not one of the above.
+    pub fn detect() -> Self {
+        match shell_name(&std::env::var("SHELL").unwrap_or_default()).as_str() {
+            "zsh" => HistoryShell::Zsh,
+            "fish" => HistoryShell::Fish,
+            _ => HistoryShell::Bash,
+        }
+    }
+
+    /// `$HISTFILE` if it is set for bash and zsh, otherwise the default
+    /// location of the history file.
+    pub fn history_path(&self) -> PathBuf {
+        let home = dirs::home_dir().unwrap_or_default();
+        let histfile = std::env::var("HISTFILE").ok().filter(|f| !f.is_empty());
+
+        match self {
+            HistoryShell::Bash => histfile
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".bash_history")),
+            HistoryShell::Zsh => histfile.map(PathBuf::from).unwrap_or_else(|| {
+                let dir = std::env::var("ZDOTDIR").map(PathBuf::from).unwrap_or(home);
+                dir.join(".zsh_history")
+            }),
+            HistoryShell::Fish => dirs::data_dir()
+                .unwrap_or_else(|| home.join(".local/share"))
+                .join("fish/fish_history"),
+        }
+    }
+
+    /// Commands in a history file, oldest first.
+    pub fn parse(&self, content: &[u8]) -> Vec<String> {
+        match self {
+            HistoryShell::Bash => parse_bash(&String::from_utf8_lossy(content)),
+            HistoryShell::Zsh => parse_zsh(&String::from_utf8_lossy(&unmetafy(content))),
+            HistoryShell::Fish => parse_fish(&String::from_utf8_lossy(content)),
+        }
+    }
+}
+
+/// All commands in the shell's history file, oldest first, without shelf's
+/// own invocations.
+pub fn read_history(shell: HistoryShell) -> Result<Vec<String>> {
+    let path = shell.history_path();
+    let content = fs::read(&path)
+        .with_context(|| format!("Could not read history file {}", path.display()))?;
+
+    Ok(shell
+        .parse(&content)
+        .into_iter()
+        .filter(|command| !is_shelf_invocation(command))
+        .collect())
+}
+
+/// The last `count` commands in the shell's history, oldest first.
+pub fn recent_commands(shell: HistoryShell, count: usize) -> Result<Vec<String>> {
+    let mut commands = read_history(shell)?;
+    let skip = commands.len().saturating_sub(count);
+    Ok(commands.split_off(skip))
+}
+
+/// The program a command runs, e.g. `git` for `sudo /usr/bin/git pull`,
+/// skipping leading variable assignments and `sudo`.
+pub fn program_name(command: &str) -> Option<String> {
+    let program = command
+        .split_whitespace()
+        .find(|word| !word.contains('=') && *word != "sudo")?;
+    let name = program.rsplit('/').next().unwrap_or(program);
+    let is_word = name
+        .chars()
+        .all(|c| c.is_alphanumeric() || "-_.".contains(c));
+    (is_word && !name.is_empty()).then(|| name.to_string())
+}
+
+fn is_shelf_invocation(command: &str) -> bool {
+    command
+        .split_whitespace()
+        .next()
+        .is_some_and(|program| program == "shelf" || program.ends_with("/shelf"))
+}
+
+/// One command per line. Lines starting with `#` followed by digits are
+/// timestamps written with `HISTTIMEFORMAT`.
+fn parse_bash(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| {
+            let is_timestamp = line
+                .strip_prefix('#')
+                .is_some_and(|t| !t.is_empty() && t.bytes().all(|b| b.is_ascii_digit()));
+            !is_timestamp && !line.trim().is_empty()
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Plain lines, or `: <start>:<elapsed>;<command>` with `EXTENDED_HISTORY`.
+/// Multi-line commands continue on lines ending with a backslash.
+fn parse_zsh(content: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in content.lines() {
+        let line = match current.take() {
+            Some(mut command) => {
+                command.push('\n');
+                command.push_str(line);
+                command
+            }
+            None => match line.strip_prefix(": ").and_then(|l| l.split_once(';')) {
+                Some((_, command)) => command.to_string(),
+                None => line.to_string(),
+            },
+        };
+
+        match line.strip_suffix('\\') {
+            Some(continued) => current = Some(continued.to_string()),
+            None => commands.push(line),
+        }
+    }
+    commands.extend(current);
+
+    commands.retain(|command| !command.trim().is_empty());
+    commands
+}
+
+/// Undoes zsh's metafication of history files: bytes that are special to
+/// zsh are written as 0x83 followed by the byte xor 0x20.
+fn unmetafy(content: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(content.len());
+    let mut iter = content.iter();
+    while let Some(&byte) = iter.next() {
+        match byte {
+            0x83 => bytes.extend(iter.next().map(|next| next ^ 0x20)),
+            _ => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+/// A YAML-like list of `- cmd: <command>` entries with `when:` and `paths:`
+/// keys. Newlines and backslashes in commands are escaped.
+fn parse_fish(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| line.strip_prefix("- cmd: "))
+        .map(unescape_fish)
+        .filter(|command| !command.trim().is_empty())
+        .collect()
+}
+
+fn unescape_fish(command: &str) -> String {
+    let mut unescaped = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_history_files() {
+        let bash = "ls -la\n#1700000000\ngit status\n\n";
+        assert_eq!(
+            HistoryShell::Bash.parse(bash.as_bytes()),
+            ["ls -la", "git status"]
+        );
+
+        let zsh = b": 1700000000:0;ls -la\n: 1700000005:2;for f in *; do\\\necho $f\\\ndone\n\
+                    plain command\n: 1700000009:0;echo \xc3\x83\xa4\n";
+        assert_eq!(
+            HistoryShell::Zsh.parse(zsh),
+            [
+                "ls -la",
+                "for f in *; do\necho $f\ndone",
+                "plain command",
+                "echo Ä",
+            ]
+        );
+
+        let fish = "- cmd: ls -la\n  when: 1700000000\n- cmd: echo a\\\\nb\\nls\n  when: 1700000001\n  paths:\n    - /tmp\n";
+        assert_eq!(
+            HistoryShell::Fish.parse(fish.as_bytes()),
+            ["ls -la", "echo a\\nb\nls"]
+        );
+    }
+
+    #[test]
+    fn test_program_name() {
+        assert_eq!(program_name("git pull --rebase").as_deref(), Some("git"));
+        assert_eq!(
+            program_name("RUST_LOG=debug sudo /usr/bin/docker ps").as_deref(),
+            Some("docker")
+        );
+        assert_eq!(program_name("(cd src && make)"), None);
+        assert_eq!(program_name("  "), None);
+    }
+
+    #[test]
+    fn test_skips_shelf_invocations() {
+        assert!(is_shelf_invocation("shelf stack --last"));
+        assert!(is_shelf_invocation("~/.cargo/bin/shelf list"));
+        assert!(!is_shelf_invocation("shelfctl list"));
+        assert!(!is_shelf_invocation("echo shelf"));
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+/// Reads and deserializes a TOML file, or returns `None` if it does not exist.
+pub fn read_toml<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let value = toml::from_str(&content)
+        .with_context(|| format!("Could not get toml data from {}", path.display()))?;
+
+    Ok(Some(value))
+}
+
+/// Serializes `value` to a temporary file next to `path` and renames it into
+/// place, so readers only ever see the old or the new contents and a crash
+/// mid-write cannot truncate the file.
+pub fn write_toml_atomic<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let toml_string = toml::to_string(value).context("Could not serialize data toml to string!")?;
+    write_atomic(path, toml_string.as_bytes())
+}
+
+/// Writes `content` to `path` the same way as `write_toml_atomic`.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let dir = parent_dir(path);
+    fs::create_dir_all(dir).context("Could not create data directory")?;
+
+    let mut temp = NamedTempFile::new_in(dir).context("Could not create temporary file")?;
+    temp.write_all(content)
+        .context("Could not write temporary file")?;
+    temp.as_file()
+        .sync_all()
+        .context("Could not flush temporary file")?;
+    temp.persist(path)
+        .with_context(|| format!("Could not replace {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Runs `f` while holding an exclusive advisory lock for `path`. Concurrent
+/// shelf processes block here, which serializes read-modify-write cycles.
+///
+/// The lock is taken on a sibling `.lock` file rather than `path` itself,
+/// because atomic writes replace `path` with a new inode.
+pub fn with_lock<R>(path: &Path, f: impl FnOnce() -> Result<R>) -> Result<R> {
+    let lock_path = lock_path(path);
+    fs::create_dir_all(parent_dir(path)).context("Could not create data directory")?;
+
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Could not open lock file {}", lock_path.display()))?;
+    lock_file
+        .lock()
+        .with_context(|| format!("Could not lock {}", lock_path.display()))?;
+
+    let result = f();
+
+    unlock(&lock_file);
+    result
+}
+
+/// Read-modify-write of a TOML file under its lock. A missing file starts
+/// out as `T::default()`. Nothing is written if `f` returns an error.
+pub fn update_toml<T, R>(path: &Path, f: impl FnOnce(&mut T) -> Result<R>) -> Result<R>
+where
+    T: Default + Serialize + DeserializeOwned,
+{
+    with_lock(path, || {
+        let mut value = read_toml::<T>(path)?.unwrap_or_default();
+        let result = f(&mut value)?;
+        write_toml_atomic(path, &value)?;
+        Ok(result)
+    })
+}
+
+fn unlock(file: &File) {
+    // Closing the file releases the lock too, so a failure here is harmless
+    let _ = file.unlock();
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".lock");
+    path.with_file_name(name)
+}
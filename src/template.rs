@@ -0,0 +1,1141 @@
+//! Command templates.
+//!
+//! A placeholder has the form `{{name|help:default}}`, where both the help
+//! text and the default are optional:
+//!
+//! ```text
+//! {{host}}                    plain parameter
+//! {{user:admin}}              "admin" is used when the prompt is left empty
+//! {{host|The bastion host}}   help text shown in the prompt
+//! {{user|Login user:admin}}   both
+//! ```
+//!
+//! When the part after `:` contains a `|` it is a list of choices instead of
+//! a default, and when it is wrapped in `$(...)` the choices are the output
+//! lines of that shell command. Either way the value is picked with the
+//! fuzzy picker:
+//!
+//! ```text
+//! {{env:dev|staging|prod}}
+//! {{container|Container:$(docker ps --format '{{.Names}}')}}
+//! ```
+//!
+//! The spec may start with `:`-separated modifiers that constrain the value.
+//! Anything after them is the default or the choices as above:
+//!
+//! ```text
+//! {{port:int:8080}}           a whole number, 8080 by default
+//! {{file:path}}               an existing path, tab-completed when prompted
+//! {{tag:required}}            must not be empty
+//! {{name:regex=^[a-z-]+$}}    must match; the regex runs to the closing }}
+//! ```
+//!
+//! Values are shell-quoted when spliced in, so each one stays a single word
+//! whatever it contains. `{{!name}}` splices the value raw instead, for
+//! values that are meant to be several words or shell syntax.
+//!
+//! `\{{text}}` is kept as a literal `{{text}}`, and `{{...}}` that does not
+//! start with a parameter name (e.g. `{{.Names}}`) is left untouched.
+
+use anyhow::{anyhow, Result};
+use colored::*;
+use std::collections::{BTreeMap, HashMap};
+
+use regex::Regex;
+
+use crate::{
+    fuzzy::FuzzyPicker,
+    param_history::{PreviousValues, RecentValues},
+    prompt::{expand_tilde, read_line},
+    shell::{quote_in, quote_state_after, shell_command, QuoteState},
+};
+
+/// A template parameter as declared by its first placeholder in a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateParam {
+    pub name: String,
+    pub help: Option<String>,
+    pub default: Option<String>,
+    pub choices: Option<Choices>,
+    pub kind: ParamKind,
+    pub required: bool,
+    pub pattern: Option<ParamPattern>,
+}
+
+/// A `regex=` constraint, compiled once when the template is parsed. An
+/// invalid regex is kept with its error, which `check_template` reports.
+#[derive(Clone, Debug)]
+pub struct ParamPattern {
+    source: String,
+    regex: Result<Regex, String>,
+}
+
+impl ParamPattern {
+    fn new(source: &str) -> Self {
+        ParamPattern {
+            source: source.to_string(),
+            regex: Regex::new(source).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for ParamPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for ParamPattern {}
+
+impl std::fmt::Display for ParamPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// The type of value a parameter takes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParamKind {
+    #[default]
+    Text,
+    /// A whole number
+    Int,
+    /// An existing file or directory; `~` is expanded
+    Path,
+}
+
+/// Values a parameter is picked from instead of typed in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Choices {
+    /// A fixed list, `{{env:dev|staging|prod}}`
+    List(Vec<String>),
+    /// Output lines of a shell command, `{{container:$(docker ps)}}`
+    Command(String),
+}
+
+impl TemplateParam {
+    fn parse(inner: &str) -> Option<Self> {
+        let name_len = inner
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(inner.len());
+        if name_len == 0 {
+            return None;
+        }
+
+        let (name, mut rest) = inner.split_at(name_len);
+        let mut help = None;
+        let mut default = None;
+        let mut choices = None;
+        let mut kind = ParamKind::Text;
+        let mut required = false;
+        let mut pattern = None;
+
+        if let Some(after_bar) = rest.strip_prefix('|') {
+            let help_len = after_bar.find(':').unwrap_or(after_bar.len());
+            help = Some(after_bar[..help_len].trim().to_string()).filter(|h| !h.is_empty());
+            rest = &after_bar[help_len..];
+        }
+
+        if let Some(mut spec) = rest.strip_prefix(':') {
+            // Leading modifiers; `regex=` takes the rest of the spec
+            loop {
+                let (word, after) = match spec.split_once(':') {
+                    Some((word, after)) => (word, Some(after)),
+                    None => (spec, None),
+                };
+                match word {
+                    "int" => kind = ParamKind::Int,
+                    "path" => kind = ParamKind::Path,
+                    "required" => required = true,
+                    _ => {
+                        if let Some(regex) = spec.strip_prefix("regex=") {
+                            pattern = Some(ParamPattern::new(regex));
+                            spec = "";
+                        }
+                        break;
+                    }
+                }
+                match after {
+                    Some(after) => spec = after,
+                    None => {
+                        spec = "";
+                        break;
+                    }
+                }
+            }
+
+            let modifiers = kind != ParamKind::Text || required || pattern.is_some();
+            if spec.is_empty() && modifiers {
+                // Only modifiers, no default
+            } else if let Some(command) = spec.strip_prefix("$(").and_then(|s| s.strip_suffix(')'))
+            {
+                choices = Some(Choices::Command(command.trim().to_string()));
+            } else if spec.contains('|') {
+                let list = spec
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|choice| !choice.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                choices = Some(Choices::List(list));
+            } else {
+                default = Some(spec.to_string());
+            }
+        } else if !rest.is_empty() {
+            return None;
+        }
+
+        Some(TemplateParam {
+            name: name.to_string(),
+            help,
+            default,
+            choices,
+            kind,
+            required,
+            pattern,
+        })
+    }
+
+    /// Fills in whatever this declaration leaves unset from a later
+    /// placeholder with the same name.
+    fn merge(&mut self, other: TemplateParam) {
+        if self.help.is_none() {
+            self.help = other.help;
+        }
+        if self.default.is_none() {
+            self.default = other.default;
+        }
+        if self.choices.is_none() {
+            self.choices = other.choices;
+        }
+        if self.kind == ParamKind::Text {
+            self.kind = other.kind;
+        }
+        if self.pattern.is_none() {
+            self.pattern = other.pattern;
+        }
+        self.required |= other.required;
+    }
+
+    /// Checks `value` against the parameter's type and constraints and
+    /// returns the value to splice in, or why it was rejected.
+    pub fn validate(&self, value: &str) -> Result<String, String> {
+        if value.is_empty() && self.required {
+            return Err("a value is required".to_string());
+        }
+
+        if let Some(Choices::List(choices)) = &self.choices {
+            if !choices.iter().any(|choice| choice == value) {
+                return Err(format!("choices are: {}", choices.join(", ")));
+            }
+        }
+
+        if let Some(pattern) = &self.pattern {
+            let regex = pattern
+                .regex
+                .as_ref()
+                .map_err(|e| format!("invalid regex '{}' in template: {}", pattern, e))?;
+            if !regex.is_match(value) {
+                return Err(format!("must match {}", pattern));
+            }
+        }
+
+        match self.kind {
+            ParamKind::Text => Ok(value.to_string()),
+            // Left empty, unless the parameter is also required
+            ParamKind::Int if value.is_empty() => Ok(String::new()),
+            ParamKind::Int => value
+                .parse::<i64>()
+                .map(|_| value.to_string())
+                .map_err(|_| "expected a whole number".to_string()),
+            ParamKind::Path => {
+                let path = expand_tilde(value);
+                if value.is_empty() || !path.exists() {
+                    return Err("no such file or directory".to_string());
+                }
+                Ok(path.to_string_lossy().into_owned())
+            }
+        }
+    }
+}
+
+enum Segment {
+    Text(String),
+    /// An escaped `\{{`, which stands for a literal `{{`
+    Escaped,
+    Param {
+        param: TemplateParam,
+        raw: bool,
+        /// The placeholder as written, braces included
+        source: String,
+    },
+}
+
+/// Splits a command into literal text and placeholders.
+fn parse_template(command: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut rest = command;
+
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            text.push_str(&rest[..start - 1]);
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Escaped);
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        text.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+
+        let parsed = find_closing(after_open).and_then(|end| {
+            let inner = &after_open[..end];
+            let (inner, raw) = match inner.strip_prefix('!') {
+                Some(inner) => (inner, true),
+                None => (inner, false),
+            };
+            TemplateParam::parse(inner).map(|param| (param, raw, end))
+        });
+
+        match parsed {
+            Some((param, raw, end)) => {
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Param {
+                    param,
+                    raw,
+                    source: rest[start..start + end + 4].to_string(),
+                });
+                rest = &after_open[end + 2..];
+            }
+            None => {
+                text.push_str("{{");
+                rest = after_open;
+            }
+        }
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+
+    segments
+}
+
+/// Byte offset of the `}}` closing a placeholder, skipping over nested
+/// `{{...}}` pairs inside it.
+fn find_closing(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i + 1 < bytes.len() {
+        match &bytes[i..i + 2] {
+            b"{{" => {
+                depth += 1;
+                i += 2;
+            }
+            b"}}" if depth == 0 => return Some(i),
+            b"}}" => {
+                depth -= 1;
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+/// Unique parameters of a command, in order of first appearance.
+pub fn parse_parameters(command: &str) -> Vec<TemplateParam> {
+    let mut params: Vec<TemplateParam> = Vec::new();
+
+    for segment in parse_template(command) {
+        if let Segment::Param { param, .. } = segment {
+            match params.iter_mut().find(|p| p.name == param.name) {
+                Some(existing) => existing.merge(param),
+                None => params.push(param),
+            }
+        }
+    }
+
+    params
+}
+
+/// Checks the constraints of every placeholder in a command, so a template
+/// with an invalid `regex=` is rejected when it is stacked rather than when
+/// it is run.
+pub fn check_template(command: &str) -> Result<()> {
+    for param in parse_parameters(command) {
+        if let Some(ParamPattern {
+            source,
+            regex: Err(e),
+        }) = &param.pattern
+        {
+            return Err(anyhow!(
+                "Invalid regex '{}' for {}: {}",
+                source,
+                param.name,
+                e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Unique parameter names of a command, in order of first appearance.
+pub fn extract_parameters(command: &str) -> Vec<String> {
+    parse_parameters(command)
+        .into_iter()
+        .map(|param| param.name)
+        .collect()
+}
+
+/// Template values given on the command line with `--set name=value`,
+/// positionally after `--`, or as the values of the previous run with
+/// `--last`.
+#[derive(Default)]
+pub struct ProvidedValues {
+    pub named: Vec<(String, String)>,
+    pub positional: Vec<String>,
+    pub last: bool,
+}
+
+impl ProvidedValues {
+    pub fn is_empty(&self) -> bool {
+        self.named.is_empty() && self.positional.is_empty() && !self.last
+    }
+}
+
+/// Parses a `--set name=value` argument.
+pub fn parse_set_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected name=value, got '{}'", arg)),
+    }
+}
+
+/// Works out a value for every parameter. Values given on the command line
+/// win, then `variables` of the same name, or of any case if none matches
+/// exactly; the rest are prompted for when `interactive`, or take their
+/// defaults otherwise. Errors list whatever is still missing instead of
+/// blocking on a prompt nobody can answer. Dynamic choices are listed by
+/// running their command with `shell`. `previous` values are offered first
+/// in prompts, and `--last` takes the values of the last run from it.
+pub fn resolve_values(
+    parameters: &[TemplateParam],
+    provided: &ProvidedValues,
+    variables: &BTreeMap<String, String>,
+    previous: &PreviousValues,
+    interactive: bool,
+    shell: &str,
+) -> Result<HashMap<String, String>> {
+    if provided.positional.len() > parameters.len() {
+        return Err(anyhow!(
+            "Got {} positional values, but the template only has {} parameters ({})",
+            provided.positional.len(),
+            parameters.len(),
+            parameter_names(parameters)
+        ));
+    }
+
+    let mut values = HashMap::new();
+
+    for param in parameters {
+        let value = variables.get(&param.name).or_else(|| {
+            variables
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&param.name))
+                .map(|(_, value)| value)
+        });
+        if let Some(value) = value {
+            values.insert(param.name.clone(), value.clone());
+        }
+    }
+
+    if let (true, Some(last)) = (provided.last, &previous.last) {
+        for param in parameters {
+            if let Some(value) = last.get(&param.name) {
+                values.insert(param.name.clone(), value.clone());
+            }
+        }
+    }
+
+    for (param, value) in parameters.iter().zip(&provided.positional) {
+        values.insert(param.name.clone(), value.clone());
+    }
+
+    for (name, value) in &provided.named {
+        if !parameters.iter().any(|param| &param.name == name) {
+            return Err(anyhow!(
+                "Unknown template parameter '{}'. Parameters are: {}",
+                name,
+                parameter_names(parameters)
+            ));
+        }
+        values.insert(name.clone(), value.clone());
+    }
+
+    for param in parameters {
+        if let Some(value) = values.get_mut(&param.name) {
+            *value = param
+                .validate(value)
+                .map_err(|e| invalid_value(param, value, &e))?;
+        }
+    }
+
+    let remaining: Vec<TemplateParam> = parameters
+        .iter()
+        .filter(|param| !values.contains_key(&param.name))
+        .cloned()
+        .collect();
+
+    if remaining.is_empty() {
+        return Ok(values);
+    }
+
+    if interactive {
+        eprintln!(
+            "{}",
+            "This is a template command. Please provide values:".yellow()
+        );
+        values.extend(prompt_for_parameters(&remaining, &previous.recent, shell)?);
+        return Ok(values);
+    }
+
+    let mut missing = Vec::new();
+    for param in remaining {
+        match &param.default {
+            Some(default) => {
+                let value = param
+                    .validate(default)
+                    .map_err(|e| invalid_value(&param, default, &e))?;
+                values.insert(param.name, value);
+            }
+            None => missing.push(param.name),
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Missing values for template parameters: {}. Pass them with --set name=value",
+            missing.join(", ")
+        ));
+    }
+
+    Ok(values)
+}
+
+fn invalid_value(param: &TemplateParam, value: &str, reason: &str) -> anyhow::Error {
+    anyhow!("Invalid value '{}' for {}: {}", value, param.name, reason)
+}
+
+fn parameter_names(parameters: &[TemplateParam]) -> String {
+    parameters
+        .iter()
+        .map(|param| param.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn prompt_for_parameters(
+    parameters: &[TemplateParam],
+    recent: &RecentValues,
+    shell: &str,
+) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+
+    for param in parameters {
+        let previous = recent.get(&param.name).map(Vec::as_slice).unwrap_or(&[]);
+
+        if let Some(choices) = &param.choices {
+            let value = pick_choice(param, choices, previous, shell)?;
+            eprintln!("{} {}", param.name.yellow().bold(), value.cyan());
+            values.insert(param.name.clone(), value);
+            continue;
+        }
+
+        // The value used last time beats the declared default
+        let default = previous.first().or(param.default.as_ref());
+
+        let value = loop {
+            let mut value = match read_value(param, default, previous)? {
+                Some(input) => input.trim().to_string(),
+                None => return Err(anyhow!("No value entered for {}", param.name)),
+            };
+
+            if value.is_empty() {
+                if let Some(default) = default {
+                    value = default.clone();
+                }
+            }
+
+            match param.validate(&value) {
+                Ok(value) => break value,
+                Err(e) => eprintln!("{}", format!("Invalid {}: {}", param.name, e).red()),
+            }
+        };
+
+        values.insert(param.name.clone(), value);
+    }
+
+    Ok(values)
+}
+
+/// Prompts for a single value, with `previous` values one Up arrow or
+/// Ctrl-R away. `None` means the prompt was cancelled.
+fn read_value(
+    param: &TemplateParam,
+    default: Option<&String>,
+    previous: &[String],
+) -> Result<Option<String>> {
+    let mut prompt = format!("Enter {}", param.name.yellow().bold());
+    if let Some(help) = &param.help {
+        prompt.push_str(&format!(" {}", format!("({})", help).bright_black()));
+    }
+    match param.kind {
+        ParamKind::Text => {}
+        ParamKind::Int => prompt.push_str(&format!(" {}", "<int>".bright_black())),
+        ParamKind::Path => prompt.push_str(&format!(" {}", "<path>".bright_black())),
+    }
+    if let Some(default) = default {
+        prompt.push_str(&format!(" [{}]", default.cyan()));
+    }
+    prompt.push_str(": ");
+
+    read_line(&prompt, param.kind == ParamKind::Path, previous)
+}
+
+/// Picks one of the choices, listing the `previous` values that are still
+/// available first.
+fn pick_choice(
+    param: &TemplateParam,
+    choices: &Choices,
+    previous: &[String],
+    shell: &str,
+) -> Result<String> {
+    let mut choices = match choices {
+        Choices::List(list) => list.clone(),
+        Choices::Command(command) => run_choice_command(command, shell)?,
+    };
+    choices.sort_by_key(|choice| {
+        previous
+            .iter()
+            .position(|p| p == choice)
+            .unwrap_or(usize::MAX)
+    });
+
+    if choices.is_empty() {
+        return Err(anyhow!("No choices available for {}", param.name));
+    }
+
+    let mut picker = FuzzyPicker::new(&choices);
+    match picker.pick() {
+        Ok(Some(choice)) => Ok(choice),
+        Ok(None) => Err(anyhow!("No value selected for {}", param.name)),
+        Err(e) => Err(anyhow!("Could not pick a value for {}: {}", param.name, e)),
+    }
+}
+
+/// Non-empty output lines of a dynamic choice command.
+fn run_choice_command(command: &str, shell: &str) -> Result<Vec<String>> {
+    let output = shell_command(shell, command)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("Could not run '{}' with {}: {}", command, shell, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Choice command '{}' failed with status: {}",
+            command,
+            output.status
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// `text` with every `{{` escaped, so it is kept as it is rather than read
+/// as a template.
+pub fn escape_placeholders(text: &str) -> String {
+    text.replace("{{", "\\{{")
+}
+
+/// Replaces every placeholder with its value, quoted for `shell` unless the
+/// placeholder is raw, and unescapes `\{{`. A placeholder the command
+/// already wraps in quotes, e.g. `"{{msg}}"`, has its value escaped for
+/// inside those quotes instead, so templates written before values were
+/// quoted keep working.
+pub fn interpolate_command(command: &str, values: &HashMap<String, String>, shell: &str) -> String {
+    let mut state = QuoteState::Unquoted;
+    parse_template(command)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => {
+                state = quote_state_after(shell, &text, state);
+                text
+            }
+            Segment::Escaped => "{{".to_string(),
+            Segment::Param { param, raw, .. } => {
+                let value = values.get(&param.name).map(String::as_str).unwrap_or("");
+                if raw {
+                    value.to_string()
+                } else {
+                    quote_in(shell, value, state)
+                }
+            }
+        })
+        .collect()
+}
+
+/// The command as written, with every placeholder passed through
+/// `highlight`, e.g. to color it.
+pub fn highlight_placeholders(command: &str, highlight: impl Fn(&str) -> String) -> String {
+    parse_template(command)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text,
+            Segment::Escaped => "\\{{".to_string(),
+            Segment::Param { source, .. } => highlight(&source),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(name: &str, help: Option<&str>, default: Option<&str>) -> TemplateParam {
+        TemplateParam {
+            name: name.to_string(),
+            help: help.map(str::to_string),
+            default: default.map(str::to_string),
+            choices: None,
+            kind: ParamKind::Text,
+            required: false,
+            pattern: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_defaults_and_help() {
+        let params = parse_parameters(
+            "ssh {{user:admin}}@{{host|The bastion host}} -p {{port|SSH port:22}} {{user}}",
+        );
+        assert_eq!(
+            params,
+            vec![
+                param("user", None, Some("admin")),
+                param("host", Some("The bastion host"), None),
+                param("port", Some("SSH port"), Some("22")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_defaults_may_contain_colons() {
+        let params = parse_parameters("curl {{url:http://localhost:8080}}");
+        assert_eq!(
+            params,
+            vec![param("url", None, Some("http://localhost:8080"))]
+        );
+    }
+
+    #[test]
+    fn test_non_parameters_are_left_alone() {
+        let command = r"docker ps --format '{{.Names}}' && echo \{{literal}} {{ spaced }}";
+        assert!(parse_parameters(command).is_empty());
+        assert_eq!(
+            interpolate_command(command, &HashMap::new(), "sh"),
+            "docker ps --format '{{.Names}}' && echo {{literal}} {{ spaced }}"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_replaces_every_occurrence() {
+        let values = HashMap::from([
+            ("user".to_string(), "root".to_string()),
+            ("host".to_string(), "db1".to_string()),
+        ]);
+        assert_eq!(
+            interpolate_command(
+                "ssh {{user:admin}}@{{host}} # {{user}} \\{{host}}",
+                &values,
+                "sh"
+            ),
+            "ssh root@db1 # root {{host}}"
+        );
+    }
+
+    #[test]
+    fn test_highlight_placeholders() {
+        let command = r"docker ps --format '{{.Names}}' \{{x}} {{name|Name:$(ls {{y}})}} {{!raw}}";
+        assert_eq!(
+            highlight_placeholders(command, |p| format!("<{}>", p)),
+            r"docker ps --format '{{.Names}}' \{{x}} <{{name|Name:$(ls {{y}})}}> <{{!raw}}>"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_quotes_unless_raw() {
+        let command = "grep {{pattern}} {{!files}} {{!pattern}}";
+        assert_eq!(extract_parameters(command), vec!["pattern", "files"]);
+
+        let values = HashMap::from([
+            ("pattern".to_string(), "it's $HOME; `id`\nnext".to_string()),
+            ("files".to_string(), "*.rs src/*.rs".to_string()),
+        ]);
+        assert_eq!(
+            interpolate_command(command, &values, "sh"),
+            "grep 'it'\\''s $HOME; `id`\nnext' *.rs src/*.rs it's $HOME; `id`\nnext"
+        );
+        assert_eq!(
+            interpolate_command("echo {{pattern}}", &values, "fish"),
+            "echo 'it\\'s $HOME; `id`\nnext'"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_inside_quotes() {
+        let values = HashMap::from([("msg".to_string(), "fix \"bug\" in $HOME's".to_string())]);
+        assert_eq!(
+            interpolate_command("git commit -m \"{{msg}}\"", &values, "sh"),
+            "git commit -m \"fix \\\"bug\\\" in \\$HOME's\""
+        );
+        assert_eq!(
+            interpolate_command("echo 'msg: {{msg}}' {{msg}}", &values, "sh"),
+            "echo 'msg: fix \"bug\" in $HOME'\\''s' 'fix \"bug\" in $HOME'\\''s'"
+        );
+        assert_eq!(
+            interpolate_command("echo \"{{!msg}}\"", &values, "sh"),
+            "echo \"fix \"bug\" in $HOME's\""
+        );
+    }
+
+    #[test]
+    fn test_resolve_provided_values() {
+        let params = parse_parameters("ssh {{user}}@{{host}} -p {{port:22}}");
+
+        let provided = ProvidedValues {
+            named: vec![("host".to_string(), "db1".to_string())],
+            positional: vec!["root".to_string()],
+            ..Default::default()
+        };
+        let values = resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap();
+        assert_eq!(values["user"], "root");
+        assert_eq!(values["host"], "db1");
+        assert_eq!(values["port"], "22");
+
+        let provided = ProvidedValues {
+            named: vec![("port".to_string(), "2222".to_string())],
+            positional: vec![],
+            ..Default::default()
+        };
+        let err = resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("user, host"), "{}", err);
+
+        let provided = ProvidedValues {
+            named: vec![("nope".to_string(), "x".to_string())],
+            positional: vec![],
+            ..Default::default()
+        };
+        assert!(resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh"
+        )
+        .is_err());
+
+        let provided = ProvidedValues {
+            named: vec![],
+            positional: vec!["a".into(), "b".into(), "c".into(), "d".into()],
+            ..Default::default()
+        };
+        assert!(resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh"
+        )
+        .is_err());
+
+        assert_eq!(
+            parse_set_value("msg=a=b"),
+            Ok(("msg".to_string(), "a=b".to_string()))
+        );
+        assert!(parse_set_value("novalue").is_err());
+    }
+
+    #[test]
+    fn test_last_values() {
+        let params = parse_parameters("ssh {{user}}@{{host}} -p {{port:22}} {{flags}}");
+        let previous = PreviousValues {
+            recent: RecentValues::from([
+                ("user".to_string(), vec!["admin".to_string()]),
+                ("flags".to_string(), vec!["-v".to_string()]),
+            ]),
+            last: Some(BTreeMap::from([
+                ("user".to_string(), "root".to_string()),
+                ("host".to_string(), "db1".to_string()),
+                ("flags".to_string(), String::new()),
+                ("gone".to_string(), "x".to_string()),
+            ])),
+        };
+
+        let provided = ProvidedValues {
+            named: vec![("host".to_string(), "db2".to_string())],
+            last: true,
+            ..Default::default()
+        };
+        let values =
+            resolve_values(&params, &provided, &BTreeMap::new(), &previous, false, "sh").unwrap();
+        assert_eq!(values["user"], "root");
+        assert_eq!(values["host"], "db2");
+        assert_eq!(values["port"], "22");
+        assert_eq!(values["flags"], "");
+        assert!(!values.contains_key("gone"));
+
+        // Previous values are not used unless asked for
+        let provided = ProvidedValues::default();
+        assert!(
+            resolve_values(&params, &provided, &BTreeMap::new(), &previous, false, "sh").is_err()
+        );
+    }
+
+    #[test]
+    fn test_variables_fill_parameters() {
+        let params = parse_parameters("docker push {{registry}}/{{image}}:{{tag:latest}}");
+        let variables = BTreeMap::from([
+            ("registry".to_string(), "ghcr.io/acme".to_string()),
+            ("tag".to_string(), "v1".to_string()),
+            ("unused".to_string(), "x".to_string()),
+        ]);
+
+        let provided = ProvidedValues {
+            named: vec![
+                ("image".to_string(), "web".to_string()),
+                ("tag".to_string(), "v2".to_string()),
+            ],
+            ..Default::default()
+        };
+        let values = resolve_values(
+            &params,
+            &provided,
+            &variables,
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap();
+        assert_eq!(values["registry"], "ghcr.io/acme");
+        assert_eq!(values["tag"], "v2");
+        assert!(!values.contains_key("unused"));
+
+        // Names of another case only fill in when none matches exactly
+        let params = parse_parameters("ssh {{host}} {{user}}");
+        let variables = BTreeMap::from([
+            ("HOST".to_string(), "db1".to_string()),
+            ("USER".to_string(), "root".to_string()),
+            ("user".to_string(), "admin".to_string()),
+        ]);
+        let values = resolve_values(
+            &params,
+            &ProvidedValues::default(),
+            &variables,
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap();
+        assert_eq!(values["host"], "db1");
+        assert_eq!(values["user"], "admin");
+    }
+
+    #[test]
+    fn test_parse_choices() {
+        let params = parse_parameters(
+            "kubectl --context {{env|Cluster:dev|staging|prod}} logs {{pod:$(kubectl get pods -o go-template='{{range .items}}{{.metadata.name}}{{end}}')}}",
+        );
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].help.as_deref(), Some("Cluster"));
+        assert_eq!(params[0].default, None);
+        assert_eq!(
+            params[0].choices,
+            Some(Choices::List(vec![
+                "dev".to_string(),
+                "staging".to_string(),
+                "prod".to_string()
+            ]))
+        );
+        assert_eq!(
+            params[1].choices,
+            Some(Choices::Command(
+                "kubectl get pods -o go-template='{{range .items}}{{.metadata.name}}{{end}}'"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_provided_values_must_be_a_choice() {
+        let params = parse_parameters("deploy {{env:dev|prod}}");
+
+        let provided = ProvidedValues {
+            named: vec![("env".to_string(), "prod".to_string())],
+            positional: vec![],
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_values(
+                &params,
+                &provided,
+                &BTreeMap::new(),
+                &PreviousValues::default(),
+                false,
+                "sh"
+            )
+            .unwrap()["env"],
+            "prod"
+        );
+
+        let provided = ProvidedValues {
+            named: vec![],
+            positional: vec!["qa".to_string()],
+            ..Default::default()
+        };
+        let err = resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("dev, prod"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_modifiers() {
+        let params = parse_parameters(
+            "nc {{host:required}} {{port|Port:int:8080}} {{name:regex=^[a-z]+:[0-9]+$}} {{file:path}} {{n:int:1|2}}",
+        );
+
+        assert!(params[0].required);
+        assert_eq!(params[0].default, None);
+        assert_eq!(params[1].kind, ParamKind::Int);
+        assert_eq!(params[1].help.as_deref(), Some("Port"));
+        assert_eq!(params[1].default.as_deref(), Some("8080"));
+        assert_eq!(
+            params[2].pattern.as_ref().map(ParamPattern::as_str),
+            Some("^[a-z]+:[0-9]+$")
+        );
+        assert_eq!(params[2].default, None);
+        assert_eq!(params[3].kind, ParamKind::Path);
+        assert_eq!(
+            params[4].choices,
+            Some(Choices::List(vec!["1".to_string(), "2".to_string()]))
+        );
+
+        // Words that are not modifiers are still plain defaults
+        assert_eq!(
+            parse_parameters("{{mode:fast}}")[0].default.as_deref(),
+            Some("fast")
+        );
+    }
+
+    #[test]
+    fn test_validate_values() {
+        let params = parse_parameters(
+            "{{host:required}} {{port:int}} {{name:regex=^[a-z-]+$}} {{file:path}} {{note}}",
+        );
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "").unwrap();
+        let file = file.to_string_lossy();
+
+        assert!(params[0].validate("").is_err());
+        assert!(params[4].validate("").is_ok());
+        assert!(params[1].validate("80").is_ok());
+        assert!(params[1].validate("eighty").is_err());
+        assert_eq!(params[1].validate("").as_deref(), Ok(""));
+        assert!(parse_parameters("{{n:required:int}}")[0]
+            .validate("")
+            .is_err());
+        assert!(params[2].validate("my-app").is_ok());
+        assert!(params[2].validate("My App").is_err());
+        assert_eq!(params[3].validate(&file).as_deref(), Ok(file.as_ref()));
+        assert!(params[3].validate("does/not/exist").is_err());
+
+        assert!(check_template("echo {{name:regex=^[a-z]+$}}").is_ok());
+        let err = check_template("echo {{name:regex=([a-z}}").unwrap_err();
+        assert!(err.to_string().contains("for name"), "{}", err);
+
+        let provided = ProvidedValues {
+            named: vec![("port".to_string(), "http".to_string())],
+            positional: vec![],
+            ..Default::default()
+        };
+        let err = resolve_values(
+            &params,
+            &provided,
+            &BTreeMap::new(),
+            &PreviousValues::default(),
+            false,
+            "sh",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("'http' for port"), "{}", err);
+    }
+
+    #[test]
+    fn test_run_choice_command() {
+        assert_eq!(
+            run_choice_command("printf 'a\\n\\nb\\n'", "sh").unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert!(run_choice_command("exit 1", "sh").is_err());
+    }
+}
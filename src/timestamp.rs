@@ -0,0 +1,241 @@
+//! Points in time, stored as RFC 3339 UTC strings (`2024-02-29T12:34:56Z`)
+//! so data files stay readable, and kept as Unix seconds for arithmetic.
+//! Hand-edited files may also use other offsets or native TOML datetimes.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Timestamp(seconds)
+    }
+
+    /// Seconds elapsed between this timestamp and `now`, never negative.
+    pub fn age(&self, now: Timestamp) -> i64 {
+        (now.0 - self.0).max(0)
+    }
+
+    /// Whole days elapsed between this timestamp and `now`.
+    pub fn days_before(&self, now: Timestamp) -> i64 {
+        self.age(now) / DAY
+    }
+
+    /// Rough time since this timestamp, e.g. `3 days ago`.
+    pub fn ago(&self) -> String {
+        let age = self.age(Timestamp::now());
+        let (count, unit) = match age {
+            a if a < MINUTE => return "just now".to_string(),
+            a if a < HOUR => (a / MINUTE, "minute"),
+            a if a < DAY => (a / HOUR, "hour"),
+            a if a < 30 * DAY => (a / DAY, "day"),
+            a if a < 365 * DAY => (a / (30 * DAY), "month"),
+            a => (a / (365 * DAY), "year"),
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        format!("{} {}{} ago", count, unit, plural)
+    }
+
+    /// Parses RFC 3339 with any offset, e.g. `2024-02-29 14:34:56.5+02:00`.
+    /// Like TOML, it also takes a date and time without an offset, or only a
+    /// date, which are read as UTC. Fractions of a second are dropped.
+    fn parse_rfc3339(s: &str) -> Option<Self> {
+        let (date, time) = match s.split_once(['T', 't', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+
+        let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        let mut seconds = days_from_civil(year, month, day) * DAY;
+
+        if let Some(time) = time {
+            let (time, offset) = split_offset(time)?;
+            let mut time = time.splitn(3, ':');
+            let hour = time.next()?.parse::<i64>().ok()?;
+            let minute = time.next()?.parse::<i64>().ok()?;
+            let second = time.next()?.split('.').next()?.parse::<i64>().ok()?;
+            if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..=60).contains(&second)
+            {
+                return None;
+            }
+            seconds += hour * HOUR + minute * MINUTE + second - offset;
+        }
+
+        Some(Timestamp(seconds))
+    }
+}
+
+/// Splits a time like `12:34:56+02:00` into the time and its offset from UTC
+/// in seconds. A time without an offset is taken as UTC.
+fn split_offset(time: &str) -> Option<(&str, i64)> {
+    if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        return Some((time, 0));
+    }
+    let Some(sign_at) = time.rfind(['+', '-']) else {
+        return Some((time, 0));
+    };
+
+    let (time, offset) = time.split_at(sign_at);
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let (hours, minutes) = offset[1..].split_once(':')?;
+    let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    Some((time, sign * (hours * HOUR + minutes * MINUTE)))
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Timestamp::parse_rfc3339(s).ok_or_else(|| {
+            format!(
+                "invalid timestamp '{}', expected e.g. 2024-02-29T12:34:56Z",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0.div_euclid(DAY));
+        let seconds = self.0.rem_euclid(DAY);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / HOUR,
+            seconds % HOUR / MINUTE,
+            seconds % MINUTE
+        )
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A timestamp as written in a data file: a string, or a TOML datetime.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Text(String),
+    Toml(toml::value::Datetime),
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = match RawTimestamp::deserialize(deserializer)? {
+            RawTimestamp::Text(text) => text,
+            RawTimestamp::Toml(datetime) => datetime.to_string(),
+        };
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+// Conversions between days since the epoch and proleptic Gregorian dates,
+// after Howard Hinnant's `days_from_civil` and `civil_from_days`.
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339_round_trip() {
+        for (text, seconds) in [
+            ("1970-01-01T00:00:00Z", 0),
+            ("1969-12-31T23:59:59Z", -1),
+            ("2024-02-29T12:34:56Z", 1709210096),
+        ] {
+            assert_eq!(text.parse(), Ok(Timestamp(seconds)));
+            assert_eq!(Timestamp(seconds).to_string(), text);
+        }
+
+        assert!("2024-13-01T00:00:00Z".parse::<Timestamp>().is_err());
+        assert!("2024-02-29T12:34".parse::<Timestamp>().is_err());
+        assert!("2024-02-29T12:34:56+25:00".parse::<Timestamp>().is_err());
+        assert!("yesterday".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn test_parse_offsets_and_toml_datetimes() {
+        let expected = Ok(Timestamp(1709210096));
+        for text in [
+            "2024-02-29T14:34:56+02:00",
+            "2024-02-29T07:04:56-05:30",
+            "2024-02-29t12:34:56.789z",
+            "2024-02-29 12:34:56",
+        ] {
+            assert_eq!(text.parse(), expected, "{}", text);
+        }
+        assert_eq!("2024-02-29".parse(), Ok(Timestamp(1709164800)));
+
+        #[derive(Deserialize)]
+        struct Entry {
+            at: Timestamp,
+        }
+        for text in [
+            "at = 2024-02-29T14:34:56+02:00",
+            "at = 2024-02-29T12:34:56",
+            "at = '2024-02-29T12:34:56Z'",
+        ] {
+            let entry: Entry = toml::from_str(text).unwrap();
+            assert_eq!(entry.at, Timestamp(1709210096), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_ago() {
+        let now = Timestamp::now();
+        assert_eq!(Timestamp(now.0 - 5).ago(), "just now");
+        assert_eq!(Timestamp(now.0 - HOUR).ago(), "1 hour ago");
+        assert_eq!(Timestamp(now.0 - 3 * DAY - HOUR).ago(), "3 days ago");
+    }
+}
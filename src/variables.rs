@@ -0,0 +1,287 @@
+//! Template variables: team-wide values for placeholders like `{{registry}}`
+//! that would otherwise be typed in for every command.
+//!
+//! Variables come from the `[variables]` table in config.toml, the nearest
+//! `.shelf.toml` in the current directory or its ancestors, and
+//! `SHELF_VAR_<NAME>` environment variables, each overriding the one before.
+//!
+//! A `.shelf.toml` can come with any cloned repository, so its variables are
+//! only used once the user has trusted the file as it is with
+//! `shelf vars trust`. Changing the file revokes the trust.
+
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{get_trust_path, Config},
+    storage::{read_toml, update_toml},
+};
+
+/// Name of the per-directory variables file.
+pub const LOCAL_FILE: &str = ".shelf.toml";
+
+const ENV_PREFIX: &str = "SHELF_VAR_";
+
+/// Contents of a `.shelf.toml` file.
+#[derive(Default, Serialize, Deserialize)]
+pub struct LocalFile {
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// `.shelf.toml` files the user has trusted: the SHA-256 of the contents
+/// they trusted, by canonical path.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TrustedFiles {
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+impl TrustedFiles {
+    pub fn load() -> Result<Self> {
+        Ok(read_toml(&get_trust_path())?.unwrap_or_default())
+    }
+
+    /// Whether `path` is trusted with its current contents.
+    pub fn is_trusted(&self, path: &Path) -> Result<bool> {
+        Ok(self.files.get(&trust_key(path)) == Some(&content_hash(path)?))
+    }
+
+    fn trust(&mut self, path: &Path) -> Result<()> {
+        self.files.insert(trust_key(path), content_hash(path)?);
+        Ok(())
+    }
+}
+
+fn trust_key(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+fn content_hash(path: &Path) -> Result<String> {
+    let content = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(content)))
+}
+
+/// Trusts `path` with its current contents, so its variables are used.
+pub fn trust_file(path: &Path) -> Result<()> {
+    update_toml(&get_trust_path(), |trusted: &mut TrustedFiles| {
+        trusted.trust(path)
+    })
+}
+
+/// A variable's effective value and where it was set.
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+    pub source: String,
+}
+
+/// Effective variables, and the nearest `.shelf.toml` if it was skipped
+/// because it is not trusted.
+pub struct Variables {
+    /// Sorted by name
+    pub variables: Vec<Variable>,
+    pub untrusted: Option<PathBuf>,
+}
+
+impl Variables {
+    /// Tells the user on stderr about a skipped `.shelf.toml`.
+    pub fn warn_untrusted(&self) {
+        if let Some(path) = &self.untrusted {
+            eprintln!(
+                "{} {} {}",
+                "Ignoring the variables in".yellow(),
+                path.display(),
+                "because it is not trusted. Check it, then run `shelf vars trust`".yellow()
+            );
+        }
+    }
+}
+
+/// Effective variables, by name. Warns about an untrusted `.shelf.toml`.
+pub fn resolve_variables(config: &Config) -> Result<BTreeMap<String, String>> {
+    let variables = effective_variables(config)?;
+    variables.warn_untrusted();
+    Ok(variables
+        .variables
+        .into_iter()
+        .map(|var| (var.name, var.value))
+        .collect())
+}
+
+/// Effective variables with their source.
+pub fn effective_variables(config: &Config) -> Result<Variables> {
+    let cwd = std::env::current_dir().context("Could not get the current directory")?;
+    collect_variables(config, &cwd, std::env::vars(), &TrustedFiles::load()?)
+}
+
+fn collect_variables(
+    config: &Config,
+    dir: &Path,
+    env: impl Iterator<Item = (String, String)>,
+    trusted: &TrustedFiles,
+) -> Result<Variables> {
+    let mut variables: BTreeMap<String, Variable> = BTreeMap::new();
+    let mut untrusted = None;
+
+    for (name, value) in &config.variables {
+        add_variable(&mut variables, name.clone(), value.clone(), "config");
+    }
+
+    if let Some(path) = find_local_file(dir) {
+        if trusted.is_trusted(&path)? {
+            let local = read_toml::<LocalFile>(&path)?.unwrap_or_default();
+            let source = path.display().to_string();
+            for (name, value) in local.variables {
+                add_variable(&mut variables, name, value, &source);
+            }
+        } else {
+            untrusted = Some(path);
+        }
+    }
+
+    for (key, value) in env {
+        let Some(name) = key.strip_prefix(ENV_PREFIX).filter(|name| !name.is_empty()) else {
+            continue;
+        };
+        // `SHELF_VAR_CLUSTER` overrides `cluster`; names that match nothing
+        // are kept as written
+        let name = if variables.contains_key(name) {
+            name.to_string()
+        } else {
+            variables
+                .keys()
+                .find(|existing| existing.eq_ignore_ascii_case(name))
+                .cloned()
+                .unwrap_or_else(|| name.to_string())
+        };
+        add_variable(&mut variables, name, value, "env");
+    }
+
+    Ok(Variables {
+        variables: variables.into_values().collect(),
+        untrusted,
+    })
+}
+
+fn add_variable(
+    variables: &mut BTreeMap<String, Variable>,
+    name: String,
+    value: String,
+    source: &str,
+) {
+    let source = source.to_string();
+    variables.insert(
+        name.clone(),
+        Variable {
+            name,
+            value,
+            source,
+        },
+    );
+}
+
+/// The nearest `.shelf.toml` in `dir` or one of its ancestors.
+pub fn find_local_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(LOCAL_FILE))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_variable_precedence() {
+        let root = TempDir::new().unwrap();
+        let project = root.path().join("project");
+        let nested = project.join("src/deep");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(
+            project.join(LOCAL_FILE),
+            "[variables]\ncluster = \"staging\"\nnamespace = \"web\"\n",
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config
+            .variables
+            .insert("registry".to_string(), "ghcr.io/acme".to_string());
+        config
+            .variables
+            .insert("cluster".to_string(), "prod".to_string());
+
+        let env = vec![
+            ("SHELF_VAR_NAMESPACE".to_string(), "api".to_string()),
+            ("SHELF_VAR_Region".to_string(), "eu".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let mut trusted = TrustedFiles::default();
+        trusted.trust(&project.join(LOCAL_FILE)).unwrap();
+        let variables = collect_variables(&config, &nested, env.into_iter(), &trusted).unwrap();
+        let values: Vec<(&str, &str, &str)> = variables
+            .variables
+            .iter()
+            .map(|v| (v.name.as_str(), v.value.as_str(), v.source.as_str()))
+            .collect();
+
+        let local = project.join(LOCAL_FILE).display().to_string();
+        assert_eq!(
+            values,
+            vec![
+                ("Region", "eu", "env"),
+                ("cluster", "staging", local.as_str()),
+                ("namespace", "api", "env"),
+                ("registry", "ghcr.io/acme", "config"),
+            ]
+        );
+        assert!(variables.untrusted.is_none());
+
+        let variables =
+            collect_variables(&config, root.path(), std::iter::empty(), &trusted).unwrap();
+        assert_eq!(variables.variables[0].value, "prod");
+    }
+
+    #[test]
+    fn test_untrusted_local_file_is_ignored() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(LOCAL_FILE);
+        fs::write(&path, "[variables]\ncluster = \"staging\"\n").unwrap();
+
+        let mut config = Config::default();
+        config
+            .variables
+            .insert("cluster".to_string(), "prod".to_string());
+
+        let mut trusted = TrustedFiles::default();
+        let variables =
+            collect_variables(&config, dir.path(), std::iter::empty(), &trusted).unwrap();
+        assert_eq!(variables.variables[0].value, "prod");
+        assert_eq!(variables.untrusted, Some(path.clone()));
+
+        trusted.trust(&path).unwrap();
+        let variables =
+            collect_variables(&config, dir.path(), std::iter::empty(), &trusted).unwrap();
+        assert_eq!(variables.variables[0].value, "staging");
+
+        // Trust is for the contents it was given
+        fs::write(&path, "[variables]\ncluster = \"$(rm -rf ~)\"\n").unwrap();
+        let variables =
+            collect_variables(&config, dir.path(), std::iter::empty(), &trusted).unwrap();
+        assert_eq!(variables.variables[0].value, "prod");
+        assert!(variables.untrusted.is_some());
+    }
+}